@@ -0,0 +1,226 @@
+use std::fmt;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+
+use rand::Rng;
+use time::PreciseTime;
+
+use world::World;
+use world::chunk::{Chunk, SIZE};
+use world::generator::{ChunkGenerator, TerrainGenerator};
+use world::noise;
+use world::mesh::{self, Mesh};
+
+// scenes for measuring the chunk pipeline, built in a cube of `size` chunks per axis
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Scene {
+    Solid, // every voxel filled, what Chunk::stress used to make
+    Checkerboard, // every other voxel, the most faces possible
+    Sparse, // a tenth of the voxels at random
+    Terrain, // the default terrain generator
+    Mixed, // every voxel filled with random materials
+}
+
+impl Scene {
+    pub fn all() -> Vec<Scene> {
+        vec![Scene::Solid, Scene::Checkerboard, Scene::Sparse, Scene::Terrain, Scene::Mixed]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Scene::Solid => "solid",
+            Scene::Checkerboard => "checkerboard",
+            Scene::Sparse => "sparse",
+            Scene::Terrain => "terrain",
+            Scene::Mixed => "mixed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Scene> {
+        Scene::all().into_iter().find(|scene| scene.name() == name)
+    }
+
+    pub fn build(&self, size: u32, seed: u64) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let terrain = TerrainGenerator::new(seed);
+
+        let size = size as i32;
+        for x in 0..size {
+            for z in 0..size {
+                for y in 0..size {
+                    // center terrain vertically around its surface
+                    let position = match *self {
+                        Scene::Terrain => [x, y - size / 2, z],
+                        _ => [x, y, z],
+                    };
+
+                    chunks.push(match *self {
+                        Scene::Terrain => terrain.generate(position),
+                        _ => self.fill(position, seed),
+                    });
+                }
+            }
+        }
+
+        chunks
+    }
+
+    fn fill(&self, position: [i32; 3], seed: u64) -> Chunk {
+        let mut chunk = Chunk::new(position);
+        let mut rng = noise::rng(noise::hash(seed, position));
+
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                for z in 0..SIZE {
+                    let id = match *self {
+                        Scene::Solid => 2,
+                        Scene::Checkerboard => if (x + y + z) % 2 == 0 { 2 } else { 0 },
+                        Scene::Sparse => if rng.gen_range(0, 10) == 0 { 2 } else { 0 },
+                        Scene::Mixed => rng.gen_range(1, 8),
+                        Scene::Terrain => 0,
+                    };
+
+                    if id != 0 {
+                        chunk.set([x, y, z], id);
+                    }
+                }
+            }
+        }
+
+        chunk
+    }
+}
+
+// milliseconds spent on each step for one scene
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub scene: Scene,
+    pub chunks: usize,
+    pub voxels: usize, // non-empty voxels
+    pub bytes: usize, // size of the wrld text
+
+    pub parse: f64,
+    pub write: f64,
+    pub encode: f64,
+    pub decode: f64,
+    pub instances: f64,
+    pub instance_count: usize,
+    pub mesh: f64, // greedy meshing into vertex and index lists
+    pub naive_quads: usize,
+    pub greedy_quads: usize,
+    pub mesh_matches: bool, // greedy covers exactly the faces the naive mesher does
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "{} ({} chunks, {} voxels, {} kB wrld)",
+                      self.scene.name(), self.chunks, self.voxels, self.bytes / 1024));
+        try!(writeln!(f, "  write:     {:>10.3} ms", self.write));
+        try!(writeln!(f, "  parse:     {:>10.3} ms", self.parse));
+        try!(writeln!(f, "  encode:    {:>10.3} ms", self.encode));
+        try!(writeln!(f, "  decode:    {:>10.3} ms", self.decode));
+        try!(writeln!(f, "  instances: {:>10.3} ms ({} instances)", self.instances, self.instance_count));
+        write!(f, "  mesh:      {:>10.3} ms ({} quads, {} naive){}", self.mesh, self.greedy_quads, self.naive_quads,
+               if self.mesh_matches { "" } else { " MISMATCH" })
+    }
+}
+
+fn millis(start: PreciseTime) -> f64 {
+    match start.to(PreciseTime::now()).num_microseconds() {
+        Some(us) => us as f64 / 1000.0,
+        None => 0.0,
+    }
+}
+
+// builds the scene and times each step, the scene is written to a temporary wrld file
+pub fn run(scene: Scene, size: u32, seed: u64) -> Report {
+    let chunks = scene.build(size, seed);
+
+    let voxels = chunks.iter().fold(0, |sum, chunk| {
+        sum + chunk.data.iter()
+            .flat_map(|y| y.iter())
+            .flat_map(|x| x.iter())
+            .filter(|voxel| voxel.id() != 0)
+            .count()
+    });
+
+    let start = PreciseTime::now();
+    let mut content = "".to_owned();
+    for chunk in chunks.iter() {
+        content = content + &chunk.write();
+    }
+    let write = millis(start);
+
+    let path = env::temp_dir().join(format!("overseer_bench_{}.wrld", scene.name()));
+    File::create(&path).unwrap().write_all(content.as_bytes()).unwrap();
+
+    let start = PreciseTime::now();
+    let mut world = World::new();
+    world.load_wrld(path.clone());
+    for chunk in chunks.iter() {
+        world.load_chunk(chunk.position());
+    }
+    let parse = millis(start);
+
+    let start = PreciseTime::now();
+    let encoded = chunks.iter().map(|chunk| (chunk.position(), chunk.encode())).collect::<Vec<_>>();
+    let encode = millis(start);
+
+    let start = PreciseTime::now();
+    for &(position, ref bytes) in encoded.iter() {
+        Chunk::decode(position, bytes);
+    }
+    let decode = millis(start);
+
+    // the parsed world has every chunk loaded, so faces between chunks are culled too
+    let start = PreciseTime::now();
+    let mut instances = Vec::new();
+    let mut transparent = Vec::new();
+    for chunk in world.chunks.iter() {
+        chunk.instances(&world.around(chunk.position()), &world.definitions, &mut instances, &mut transparent);
+    }
+    let instance_time = millis(start);
+
+    let start = PreciseTime::now();
+    let mut greedy_quads = 0;
+    for chunk in world.chunks.iter() {
+        let quads = mesh::greedy(chunk, &world.neighbours(chunk.position()), &world.definitions);
+        greedy_quads += quads.len();
+        Mesh::from_quads(&quads, &world.definitions);
+    }
+    let mesh_time = millis(start);
+
+    let mut naive_quads = 0;
+    let mut mesh_matches = true;
+    for chunk in world.chunks.iter() {
+        let neighbours = world.neighbours(chunk.position());
+        let naive = mesh::naive(chunk, &neighbours, &world.definitions);
+        let greedy = mesh::greedy(chunk, &neighbours, &world.definitions);
+
+        naive_quads += naive.len();
+        if mesh::coverage(&naive) != mesh::coverage(&greedy) {
+            mesh_matches = false;
+        }
+    }
+
+    let _ = ::std::fs::remove_file(&path);
+
+    Report {
+        scene: scene,
+        chunks: chunks.len(),
+        voxels: voxels,
+        bytes: content.len(),
+
+        parse: parse,
+        write: write,
+        encode: encode,
+        decode: decode,
+        instances: instance_time,
+        instance_count: instances.len() + transparent.len(),
+        mesh: mesh_time,
+        naive_quads: naive_quads,
+        greedy_quads: greedy_quads,
+        mesh_matches: mesh_matches,
+    }
+}
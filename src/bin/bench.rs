@@ -0,0 +1,29 @@
+extern crate overseer_voxel;
+
+use std::env;
+
+use overseer_voxel::bench::{self, Scene};
+
+// usage: bench [scene|all] [size] [seed]
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+
+    let scenes = match args.get(1).map(|arg| arg.as_str()) {
+        None | Some("all") => Scene::all(),
+        Some(name) => match Scene::from_name(name) {
+            Some(scene) => vec![scene],
+            None => {
+                let names = Scene::all().iter().map(|scene| scene.name()).collect::<Vec<_>>();
+                println!("Unknown scene {:?}, expected one of {:?}", name, names);
+                return;
+            },
+        },
+    };
+
+    let size = args.get(2).and_then(|arg| arg.parse::<u32>().ok()).unwrap_or(4);
+    let seed = args.get(3).and_then(|arg| arg.parse::<u64>().ok()).unwrap_or(0);
+
+    for scene in scenes {
+        println!("{}", bench::run(scene, size, seed));
+    }
+}
@@ -0,0 +1,132 @@
+use std::mem;
+use std::collections::HashMap;
+use std::collections::hash_map;
+
+use gfx;
+use gfx::traits::{Factory, Pod};
+use gfx_device_gl;
+
+pub type Encoder = gfx::Encoder<gfx_device_gl::Resources, gfx_device_gl::CommandBuffer>;
+
+// a dynamic gpu buffer that doubles whenever its contents outgrow it
+pub struct GrowableBuffer<T> {
+    pub buffer: gfx::handle::Buffer<gfx_device_gl::Resources, T>,
+    pub role: gfx::BufferRole,
+    pub capacity: usize, // elements the buffer can hold
+    pub count: u32, // elements to draw
+}
+
+impl<T: Pod> GrowableBuffer<T> {
+    pub fn new(factory: &mut gfx_device_gl::Factory, role: gfx::BufferRole, capacity: usize) -> GrowableBuffer<T> {
+        // empty buffers can't be created, so always leave room for one
+        let capacity = if capacity == 0 { 1 } else { capacity };
+
+        GrowableBuffer {
+            buffer: factory.create_buffer_dynamic(capacity, role, gfx::Bind::empty()).unwrap(),
+            role: role,
+            capacity: capacity,
+            count: 0,
+        }
+    }
+
+    // replaces the contents, returns true if the buffer had to grow
+    pub fn upload(&mut self, factory: &mut gfx_device_gl::Factory, encoder: &mut Encoder, data: &[T]) -> bool {
+        let grown = data.len() > self.capacity;
+        if grown {
+            let mut capacity = self.capacity;
+            while capacity < data.len() {
+                capacity *= 2;
+            }
+            *self = GrowableBuffer::new(factory, self.role, capacity);
+        }
+
+        if !data.is_empty() {
+            encoder.update_buffer(&self.buffer, data, 0);
+        }
+        self.count = data.len() as u32;
+
+        grown
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.capacity * mem::size_of::<T>()
+    }
+}
+
+// how full the buffers are
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Utilisation {
+    pub buffers: usize,
+    pub used: usize, // elements drawn
+    pub capacity: usize, // elements allocated
+    pub bytes: usize, // bytes allocated
+    pub grown: usize, // reallocations since the last report
+}
+
+impl Utilisation {
+    pub fn ratio(&self) -> f32 {
+        if self.capacity == 0 {
+            0.0
+        } else {
+            self.used as f32 / self.capacity as f32
+        }
+    }
+}
+
+// one growable buffer per chunk
+pub struct BufferManager<T> {
+    role: gfx::BufferRole,
+    buffers: HashMap<[i32; 3], GrowableBuffer<T>>,
+    grown: usize,
+}
+
+impl<T: Pod> BufferManager<T> {
+    pub fn new(role: gfx::BufferRole) -> BufferManager<T> {
+        BufferManager {
+            role: role,
+            buffers: HashMap::new(),
+            grown: 0,
+        }
+    }
+
+    pub fn upload(&mut self, factory: &mut gfx_device_gl::Factory, encoder: &mut Encoder, position: [i32; 3], data: &[T]) {
+        if !self.buffers.contains_key(&position) {
+            let buffer = GrowableBuffer::new(factory, self.role, data.len());
+            self.buffers.insert(position, buffer);
+        }
+
+        if let Some(buffer) = self.buffers.get_mut(&position) {
+            if buffer.upload(factory, encoder, data) {
+                self.grown += 1;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, position: [i32; 3]) {
+        self.buffers.remove(&position);
+    }
+
+    pub fn get(&self, position: [i32; 3]) -> Option<&GrowableBuffer<T>> {
+        self.buffers.get(&position)
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<[i32; 3], GrowableBuffer<T>> {
+        self.buffers.iter()
+    }
+
+    // reports and resets the reallocation count
+    pub fn utilisation(&mut self) -> Utilisation {
+        let mut utilisation = Utilisation::default();
+        for buffer in self.buffers.values() {
+            utilisation.buffers += 1;
+            utilisation.used += buffer.count as usize;
+            utilisation.capacity += buffer.capacity;
+            utilisation.bytes += buffer.bytes();
+        }
+
+        utilisation.grown = self.grown;
+        self.grown = 0;
+
+        utilisation
+    }
+}
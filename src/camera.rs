@@ -1,6 +1,7 @@
 use super::cgmath;
 use cgmath::prelude::{InnerSpace, SquareMatrix};
 use cgmath::{Vector3, Matrix4};
+use collision::Frustum;
 
 #[derive(Debug)]
 pub struct Camera {
@@ -36,6 +37,11 @@ impl Camera {
         }
     }
 
+    // the view volume in render units, for culling
+    pub fn frustum(&self) -> Option<Frustum<f32>> {
+        Frustum::from_matrix4(self.perspective * self.view)
+    }
+
     pub fn axis(&self) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
         let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
         let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
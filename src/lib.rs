@@ -15,19 +15,37 @@ extern crate rand;
 extern crate bit_set;
 
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
 
 use gfx::traits::{Factory, FactoryExt};
 use gfx::Device;
 
-use cgmath::{Matrix4};
+use cgmath::Matrix4;
+use cgmath::prelude::SquareMatrix;
+use collision::{Frustum, Relation};
 
 pub type ColorFormat = gfx::format::Rgba8;
 pub type DepthFormat = gfx::format::DepthStencil;
 
 pub mod world;
 pub mod camera;
+pub mod bench;
+pub mod buffer;
+pub mod shadow;
+pub mod light;
+pub mod sky;
 
 use camera::Camera;
+use buffer::{BufferManager, GrowableBuffer};
+use world::event::{ObserverId, WorldEvent};
+use world::chunk::InstancedVoxel;
+use world::mesh::{Mesh, MeshVertex};
+use world::lod::LodSettings;
+use world::visibility::VisibilityGraph;
+use shadow::{ShadowMaps, ShadowSettings, shadow_pipe, shadow_mesh_pipe};
+use light::{Light, LightId, Lights, MAX_LIGHTS};
+use sky::{Sky, sky_pipe};
 
 gfx_vertex_struct!( Vertex {
     pos: [f32; 4] = "vert_Pos",
@@ -35,8 +53,10 @@ gfx_vertex_struct!( Vertex {
 });
 
 gfx_constant_struct!( LightParam {
-    pos: [f32; 4] = "pos",
-    color: [f32; 4] = "color",
+    pos: [f32; 4] = "pos", // w is 0 for directional, 1 for point and 2 for spot lights
+    color: [f32; 4] = "color", // w is the intensity
+    direction: [f32; 4] = "direction", // w is the cosine of a spot's outer angle
+    attenuation: [f32; 4] = "attenuation", // w is the cosine of a spot's inner angle
     proj: [[f32; 4]; 4] = "proj",
 });
 
@@ -46,32 +66,217 @@ gfx_pipeline!( pipe {
     transform: gfx::Global<[[f32; 4]; 4]> = "c_Transform",
     voxels: gfx::InstanceBuffer<world::chunk::InstancedVoxel> = (),
     lights: gfx::ConstantBuffer<LightParam> = "b_Lights",
+    light_count: gfx::Global<i32> = "u_LightCount",
+    ambient: gfx::Global<[f32; 3]> = "u_Ambient",
+    daylight: gfx::Global<f32> = "u_Daylight",
+    shadow: gfx::TextureSampler<f32> = "t_Shadow",
+    shadow_bias: gfx::Global<f32> = "u_ShadowBias",
+    shadow_kernel: gfx::Global<i32> = "u_ShadowKernel",
     out_color: gfx::RenderTarget<ColorFormat> = "Target0",
     out_depth: gfx::DepthTarget<DepthFormat> =
         gfx::preset::depth::LESS_EQUAL_WRITE,
 });
 
+// transparent voxels, blended over what is already drawn without hiding what's behind
+gfx_pipeline!( transparent_pipe {
+    time: gfx::Global<f32> = "Time",
+    vbuf: gfx::VertexBuffer<Vertex> = (),
+    transform: gfx::Global<[[f32; 4]; 4]> = "c_Transform",
+    voxels: gfx::InstanceBuffer<world::chunk::InstancedVoxel> = (),
+    lights: gfx::ConstantBuffer<LightParam> = "b_Lights",
+    light_count: gfx::Global<i32> = "u_LightCount",
+    ambient: gfx::Global<[f32; 3]> = "u_Ambient",
+    daylight: gfx::Global<f32> = "u_Daylight",
+    shadow: gfx::TextureSampler<f32> = "t_Shadow",
+    shadow_bias: gfx::Global<f32> = "u_ShadowBias",
+    shadow_kernel: gfx::Global<i32> = "u_ShadowKernel",
+    out_color: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ALPHA),
+    out_depth: gfx::DepthTarget<DepthFormat> =
+        gfx::preset::depth::LESS_EQUAL_TEST,
+});
+
+// coarse chunks far from the camera, drawn from vertex and index buffers
+gfx_pipeline!( mesh_pipe {
+    time: gfx::Global<f32> = "Time",
+    vbuf: gfx::VertexBuffer<MeshVertex> = (),
+    transform: gfx::Global<[[f32; 4]; 4]> = "c_Transform",
+    lights: gfx::ConstantBuffer<LightParam> = "b_Lights",
+    light_count: gfx::Global<i32> = "u_LightCount",
+    ambient: gfx::Global<[f32; 3]> = "u_Ambient",
+    daylight: gfx::Global<f32> = "u_Daylight",
+    shadow: gfx::TextureSampler<f32> = "t_Shadow",
+    shadow_bias: gfx::Global<f32> = "u_ShadowBias",
+    shadow_kernel: gfx::Global<i32> = "u_ShadowKernel",
+    out_color: gfx::RenderTarget<ColorFormat> = "Target0",
+    out_depth: gfx::DepthTarget<DepthFormat> =
+        gfx::preset::depth::LESS_EQUAL_WRITE,
+});
+
+// transparent cells of coarse chunks, blended like transparent voxels
+gfx_pipeline!( transparent_mesh_pipe {
+    time: gfx::Global<f32> = "Time",
+    vbuf: gfx::VertexBuffer<MeshVertex> = (),
+    transform: gfx::Global<[[f32; 4]; 4]> = "c_Transform",
+    lights: gfx::ConstantBuffer<LightParam> = "b_Lights",
+    light_count: gfx::Global<i32> = "u_LightCount",
+    ambient: gfx::Global<[f32; 3]> = "u_Ambient",
+    daylight: gfx::Global<f32> = "u_Daylight",
+    shadow: gfx::TextureSampler<f32> = "t_Shadow",
+    shadow_bias: gfx::Global<f32> = "u_ShadowBias",
+    shadow_kernel: gfx::Global<i32> = "u_ShadowKernel",
+    out_color: gfx::BlendTarget<ColorFormat> = ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ALPHA),
+    out_depth: gfx::DepthTarget<DepthFormat> =
+        gfx::preset::depth::LESS_EQUAL_TEST,
+});
+
+// whether a chunk can be skipped, counting why in the stats
+fn culled(frustum: &Option<Frustum<f32>>, visible: &Option<HashSet<[i32; 3]>>, position: [i32; 3], stats: &mut RenderStats) -> bool {
+    if let Some(ref frustum) = *frustum {
+        if frustum.contains(&world::chunk::bounds(position)) == Relation::Out {
+            stats.culled += 1;
+            return true;
+        }
+    }
+
+    if let Some(ref visible) = *visible {
+        if !visible.contains(&position) {
+            stats.occluded += 1;
+            return true;
+        }
+    }
+
+    false
+}
+
+// squared distance from the eye to a point in render units
+fn distance(eye: [f32; 3], point: [f32; 3]) -> f32 {
+    let (x, y, z) = (point[0] - eye[0], point[1] - eye[1], point[2] - eye[2]);
+    x * x + y * y + z * z
+}
+
+// from clip space back to the world, for the sky to find where each pixel looks
+fn inverse(transform: Matrix4<f32>) -> [[f32; 4]; 4] {
+    transform.invert().unwrap_or(Matrix4::identity()).into()
+}
+
+// a point in render units in the voxel units meshes are built in
+fn voxel_eye(eye: [f32; 3]) -> [f32; 3] {
+    let scale = world::chunk::SCALE;
+    [eye[0] / scale, eye[1] / scale, eye[2] / scale]
+}
+
+// farthest first, so blending builds up in the right order
+fn sort_back_to_front(instances: &mut [InstancedVoxel], eye: [f32; 3]) {
+    let scale = world::chunk::SCALE;
+    let key = |instance: &InstancedVoxel| {
+        distance(eye, [instance.position[0] as f32 * scale, instance.position[1] as f32 * scale, instance.position[2] as f32 * scale])
+    };
+    instances.sort_by(|a, b| key(b).partial_cmp(&key(a)).unwrap());
+}
+
+// biome terrain, then caves, ores and biome decorations, caching enough for `radius` chunks around
+fn generation(world: &mut world::World, seed: u64, radius: i32) -> world::pipeline::Pipeline {
+    use world::biome::{Biome, BiomeMap, BiomeGenerator, BiomeDecorationStage};
+    use world::prefab::Prefab;
+
+    let (stone, ore) = (world.id("stone").unwrap_or(1), world.id("ore").unwrap_or(1));
+    let (trunk, leaves) = (world.id("tree").unwrap_or(1), world.id("leaves").unwrap_or(1));
+
+    // tree.wrld stands on a layer of ground and was drawn with 2 for the trunk and 3 for leaves
+    let tree = Prefab::from_wrld(PathBuf::from("world/tree.wrld")).remap(&[(1, 0), (2, trunk), (3, leaves)]);
+    let tree = if tree.voxels.is_empty() { Prefab::tree(trunk, leaves, 5) } else { tree };
+
+    let mut map = BiomeMap::new(seed);
+    let biomes = vec![
+        Biome::named(world, "plains", "grass", "dirt")
+            .map(|biome| biome.climate(0.5, 0.5).profile(0.0, 6.0).decorate(tree.clone(), 1)),
+        Biome::named(world, "forest", "grass", "dirt")
+            .map(|biome| biome.climate(0.4, 0.8).profile(4.0, 10.0).decorate(tree.clone(), 6)),
+        Biome::named(world, "desert", "sand", "sand")
+            .map(|biome| biome.climate(0.9, 0.1).profile(-2.0, 4.0)),
+        Biome::named(world, "mountains", "snow", "stone")
+            .map(|biome| biome.climate(0.1, 0.3).profile(24.0, 32.0)),
+    ];
+    for biome in biomes.into_iter().filter_map(|biome| biome) {
+        map.add(biome);
+    }
+
+    let map = Arc::new(map);
+    world.biomes = Some(map.clone());
+
+    let mut pipeline = world::pipeline::Pipeline::new(seed, BiomeGenerator::new(map.clone(), stone));
+    pipeline.add_stage(world::caves::CaveStage::new());
+    pipeline.add_stage(world::pipeline::OreStage::new(ore, stone));
+    pipeline.add_stage(BiomeDecorationStage::new(map));
+    pipeline.fit(radius);
+    pipeline
+}
+
+// what the last frame drew
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RenderStats {
+    pub drawn: usize, // chunks
+    pub culled: usize, // chunks outside the view frustum
+    pub occluded: usize, // chunks in the frustum that can't be seen from the camera's chunk
+    pub instances: usize,
+    pub triangles: usize, // from meshed chunks
+}
+
 pub struct Overseer {
     pub window: glutin::Window,
     pub device: gfx_device_gl::Device,
     pub factory: gfx_device_gl::Factory,
     pub encoder: gfx::Encoder<gfx_device_gl::Resources, gfx_device_gl::CommandBuffer>,
     pub bundle: gfx::Bundle<gfx_device_gl::Resources, pipe::Data<gfx_device_gl::Resources>>,
+    pub mesh_bundle: gfx::Bundle<gfx_device_gl::Resources, mesh_pipe::Data<gfx_device_gl::Resources>>,
+    pub transparent_bundle: gfx::Bundle<gfx_device_gl::Resources, transparent_pipe::Data<gfx_device_gl::Resources>>,
+    pub transparent_mesh_bundle: gfx::Bundle<gfx_device_gl::Resources, transparent_mesh_pipe::Data<gfx_device_gl::Resources>>,
+    pub sky_bundle: gfx::Bundle<gfx_device_gl::Resources, sky_pipe::Data<gfx_device_gl::Resources>>,
+    pub shadow_bundle: gfx::Bundle<gfx_device_gl::Resources, shadow_pipe::Data<gfx_device_gl::Resources>>,
+    pub shadow_mesh_bundle: gfx::Bundle<gfx_device_gl::Resources, shadow_mesh_pipe::Data<gfx_device_gl::Resources>>,
+    pub shadow: ShadowSettings,
+    shadow_maps: ShadowMaps,
+    pub lights: Lights,
+    light_params: Vec<LightParam>, // what was last uploaded
+    pub emissive_lights: usize, // most emissive voxels turned into lights at once, at most half of MAX_LIGHTS
+    emitter_lights: Vec<(LightId, [i32; 3])>, // slots lent to emissive voxels and the voxel each one shows
+    pub sky: Sky,
+    sun: Option<LightId>,
     pub camera: self::camera::Camera,
     pub world: world::World,
+    pub loader: world::loader::ChunkLoader,
+    pub streamer: world::stream::Streamer,
+    observer: ObserverId,
+    pub buffers: BufferManager<world::chunk::InstancedVoxel>,
+    pub mesh_buffers: BufferManager<MeshVertex>,
+    pub index_buffers: BufferManager<u16>,
+    pub transparent_buffers: BufferManager<InstancedVoxel>,
+    transparent: HashMap<[i32; 3], Vec<InstancedVoxel>>, // kept to sort again as the camera moves
+    pub transparent_mesh_buffers: BufferManager<MeshVertex>,
+    pub transparent_index_buffers: BufferManager<u16>,
+    transparent_meshes: HashMap<[i32; 3], Mesh>, // same for the coarse chunks
+    sorted_from: Option<[i32; 3]>, // camera chunk the transparent voxels were last sorted from
+    pub meshed: bool, // level 0 chunks drawn as greedy meshes rather than a voxel instance each
+    pub lod: LodSettings,
+    levels: HashMap<[i32; 3], usize>, // lod level each chunk was last built at
+    pub visibility: VisibilityGraph,
+    pub stats: RenderStats,
 }
 
 impl Overseer {
     pub fn new() -> Self {
+        Overseer::with_shadows(ShadowSettings::new())
+    }
+
+    pub fn with_shadows(shadow: ShadowSettings) -> Self {
         let mut world = world::World::new();
+        world.load_wdfn(PathBuf::from("world/test.wdfn"));
         world.load_wrld(PathBuf::from("world/wall.wrld"));
-        for x in -2..2 {
-            for z in -2..2 {
-                //world.load_chunk([x, 0, z]);
-            }
-        }
 
-        world.load_chunk([0, 0, 0]);
+        // generation keeps what neighbours need for everything the streamer holds on to
+        let mut streamer = world::stream::Streamer::new(8, 1);
+        let pipeline = generation(&mut world, 0, streamer.radius + streamer.hysteresis);
+        world.set_generator(pipeline);
 
         let vs = include_bytes!("../shader/voxel.glslv");
         let fs = include_bytes!("../shader/voxel.glslf");
@@ -89,20 +294,21 @@ impl Overseer {
 
         let camera = Camera::new(&window);
 
-        let mut instances = Vec::new();
-        for chunk in world.chunks.iter() {
-            chunk.instances(&mut instances);
-        }
+        // only the full detail chunks are loaded up front, the loader streams in the rest
+        let lod = LodSettings::new();
+        streamer.prime(&mut world, camera.position, lod.distances[0]);
+
+        let observer = world.subscribe();
 
-        let voxel_buffer = factory.create_buffer_dynamic(512, gfx::BufferRole::Vertex, gfx::Bind::empty()).unwrap();
+        // swapped for each chunk's own buffer when drawing
+        let voxel_buffer = GrowableBuffer::new(&mut factory, gfx::BufferRole::Vertex, 1).buffer;
 
         let (vertex_buffer, mut slice) = factory.create_vertex_buffer_with_slice(&world::chunk::VERTICES, world::chunk::INDICES);
-        slice.instances = Some((instances.len() as u32, 0));
-        println!("Voxels: {:?}", instances.len());
+        slice.instances = Some((0, 0));
 
         let raster = gfx::state::Rasterizer {
             front_face: gfx::state::FrontFace::CounterClockwise,
-            cull_face: gfx::state::CullFace::Nothing,
+            cull_face: gfx::state::CullFace::Back,
             //method: gfx::state::RasterMethod::Line(3),
             method: gfx::state::RasterMethod::Fill,
             offset: None,
@@ -113,59 +319,130 @@ impl Overseer {
 
         let pso = factory.create_pipeline_state(&shader_set, gfx::Primitive::TriangleList, raster, pipe::new()).unwrap();
 
+        let transparent_pso = factory.create_pipeline_state(&shader_set, gfx::Primitive::TriangleList, raster, transparent_pipe::new()).unwrap();
+
+        let mesh_vs = include_bytes!("../shader/mesh.glslv");
+        let mesh_set = factory.create_shader_set(mesh_vs, fs).unwrap();
+        let mesh_pso = factory.create_pipeline_state(&mesh_set, gfx::Primitive::TriangleList, raster, mesh_pipe::new()).unwrap();
+        let transparent_mesh_pso = factory.create_pipeline_state(&mesh_set, gfx::Primitive::TriangleList, raster, transparent_mesh_pipe::new()).unwrap();
+
+        // swapped for each chunk's own buffers when drawing
+        let mesh_slice = gfx::Slice {
+            start: 0,
+            end: 0,
+            base_vertex: 0,
+            instances: None,
+            buffer: gfx::IndexBuffer::Index16(GrowableBuffer::new(&mut factory, gfx::BufferRole::Index, 1).buffer),
+        };
+
         let pos = [25.0, 4.0, 22.0, 1.0];
         let pos2 = [25.0, 15.0, 22.0, 1.0];
 
-        let light_params = vec![LightParam {
-            pos: pos,
-            color: [ 1.0, 1.0, 1.0, 1.0],
-            proj: {
-                let mx_proj: Matrix4<_> =
-                    cgmath::PerspectiveFov {
-                        fovy: cgmath::deg(60f32).into(),
-                        aspect: 1.0,
-                        near: 1f32,
-                        far: 20f32,
-                    }.to_perspective().into();
-
-                let mx_view = cgmath::Matrix4::look_at(
-                        cgmath::Point3::new(pos[0], pos[1], pos[2]),
-                        cgmath::Point3::new(0.0, 0.0, 0.0),
-                        cgmath::Vector3::unit_z(),
-                    );
-
-                (mx_view * mx_proj).into()
+        // the sun goes first so it always gets a shadow map
+        let sky = Sky::new(600.0);
+        let mut lights = Lights::new();
+        let sun = lights.add(Light::directional(sky.sun_direction(), sky.sun_color(), sky.sun_intensity()));
+        lights.add(Light::point([pos[0], pos[1], pos[2]], [1.0, 1.0, 1.0], 1.0));
+        lights.add(Light::point([pos2[0], pos2[1], pos2[2]], [1.0, 1.0, 1.0], 1.0));
+
+        let light_params = lights.params(camera.position.into());
+
+        // filled every frame from `lights`
+        let light_buf = factory.create_buffer_dynamic(MAX_LIGHTS, gfx::BufferRole::Uniform, gfx::Bind::empty()).unwrap();
+
+        let shadow_maps = ShadowMaps::new(&mut factory, shadow.resolution, MAX_LIGHTS);
+
+        // slope scaled offset on top of the bias in the shader
+        let shadow_raster = gfx::state::Rasterizer {
+            offset: Some(gfx::state::Offset(2, 2)),
+            .. raster
+        };
+
+        let shadow_fs = include_bytes!("../shader/shadow.glslf");
+        let shadow_set = factory.create_shader_set(include_bytes!("../shader/shadow.glslv"), shadow_fs).unwrap();
+        let shadow_pso = factory.create_pipeline_state(&shadow_set, gfx::Primitive::TriangleList, shadow_raster, shadow_pipe::new()).unwrap();
+        let shadow_mesh_set = factory.create_shader_set(include_bytes!("../shader/shadow_mesh.glslv"), shadow_fs).unwrap();
+        let shadow_mesh_pso = factory.create_pipeline_state(&shadow_mesh_set, gfx::Primitive::TriangleList, shadow_raster, shadow_mesh_pipe::new()).unwrap();
+
+        let shadow_bundle = gfx::Bundle {
+            slice: slice.clone(),
+            pso: shadow_pso,
+            data: shadow_pipe::Data {
+                vbuf: vertex_buffer.clone(),
+                transform: (camera.perspective * camera.view).into(),
+                voxels: voxel_buffer.clone(),
+                out_depth: shadow_maps.targets[0].clone(),
             },
-        }, LightParam {
-            pos: pos2,
-            color: [ 1.0, 1.0, 1.0, 1.0],
-            proj: {
-                let mx_proj: Matrix4<_> =
-                    cgmath::PerspectiveFov {
-                        fovy: cgmath::deg(60f32).into(),
-                        aspect: 1.0,
-                        near: 1f32,
-                        far: 20f32,
-                    }.to_perspective().into();
-
-                let mx_view = cgmath::Matrix4::look_at(
-                        cgmath::Point3::new(pos2[0], pos2[1], pos2[2]),
-                        cgmath::Point3::new(0.0, 0.0, 0.0),
-                        cgmath::Vector3::unit_z(),
-                    );
-
-                (mx_view * mx_proj).into()
+        };
+
+        let shadow_mesh_bundle = gfx::Bundle {
+            slice: mesh_slice.clone(),
+            pso: shadow_mesh_pso,
+            data: shadow_mesh_pipe::Data {
+                vbuf: GrowableBuffer::new(&mut factory, gfx::BufferRole::Vertex, 1).buffer,
+                transform: (camera.perspective * camera.view).into(),
+                out_depth: shadow_maps.targets[0].clone(),
             },
-        }];
+        };
+
+        let mesh_data = mesh_pipe::Data {
+            time: sky.time,
+            vbuf: GrowableBuffer::new(&mut factory, gfx::BufferRole::Vertex, 1).buffer,
+            transform: (camera.perspective * camera.view).into(),
+            lights: light_buf.clone(),
+            light_count: 0,
+            ambient: sky.ambient(),
+            daylight: sky.sky_light(),
+            shadow: (shadow_maps.resource.clone(), shadow_maps.sampler.clone()),
+            shadow_bias: shadow.bias,
+            shadow_kernel: shadow.kernel,
+            out_color: main_color.clone(),
+            out_depth: main_depth.clone(),
+        };
 
-        let light_buf = factory.create_buffer_const(&light_params, gfx::BufferRole::Uniform, gfx::Bind::empty()).unwrap();
+        let transparent_mesh_data = transparent_mesh_pipe::Data {
+            time: sky.time,
+            vbuf: mesh_data.vbuf.clone(),
+            transform: (camera.perspective * camera.view).into(),
+            lights: light_buf.clone(),
+            light_count: 0,
+            ambient: sky.ambient(),
+            daylight: sky.sky_light(),
+            shadow: (shadow_maps.resource.clone(), shadow_maps.sampler.clone()),
+            shadow_bias: shadow.bias,
+            shadow_kernel: shadow.kernel,
+            out_color: main_color.clone(),
+            out_depth: main_depth.clone(),
+        };
+
+        let transparent_data = transparent_pipe::Data {
+            time: sky.time,
+            vbuf: vertex_buffer.clone(),
+            transform: (camera.perspective * camera.view).into(),
+            voxels: voxel_buffer.clone(),
+            lights: light_buf.clone(),
+            light_count: 0,
+            ambient: sky.ambient(),
+            daylight: sky.sky_light(),
+            shadow: (shadow_maps.resource.clone(), shadow_maps.sampler.clone()),
+            shadow_bias: shadow.bias,
+            shadow_kernel: shadow.kernel,
+            out_color: main_color.clone(),
+            out_depth: main_depth.clone(),
+        };
 
         let data = pipe::Data {
-            time: 0.0,
+            time: sky.time,
             vbuf: vertex_buffer,
             transform: (camera.perspective * camera.view).into(),
             voxels: voxel_buffer,
             lights: light_buf,
+            light_count: 0,
+            ambient: sky.ambient(),
+            daylight: sky.sky_light(),
+            shadow: (shadow_maps.resource.clone(), shadow_maps.sampler.clone()),
+            shadow_bias: shadow.bias,
+            shadow_kernel: shadow.kernel,
             out_color: main_color,
             out_depth: main_depth,
         };
@@ -176,35 +453,520 @@ impl Overseer {
             data: data,
         };
 
+        let transparent_bundle = gfx::Bundle {
+            slice: bundle.slice.clone(),
+            pso: transparent_pso,
+            data: transparent_data,
+        };
+
+        let transparent_mesh_bundle = gfx::Bundle {
+            slice: mesh_slice.clone(),
+            pso: transparent_mesh_pso,
+            data: transparent_mesh_data,
+        };
+
+        let mesh_bundle = gfx::Bundle {
+            slice: mesh_slice,
+            pso: mesh_pso,
+            data: mesh_data,
+        };
+
+        let sky_vs = include_bytes!("../shader/sky.glslv");
+        let sky_fs = include_bytes!("../shader/sky.glslf");
+        let sky_set = factory.create_shader_set(sky_vs, sky_fs).unwrap();
+        let sky_pso = factory.create_pipeline_state(&sky_set, gfx::Primitive::TriangleList, raster, sky_pipe::new()).unwrap();
+        let (sky_buffer, sky_slice) = factory.create_vertex_buffer_with_slice(&sky::SCREEN, ());
+
+        let sky_bundle = gfx::Bundle {
+            slice: sky_slice,
+            pso: sky_pso,
+            data: sky_pipe::Data {
+                vbuf: sky_buffer,
+                inverse: inverse(camera.perspective * camera.view),
+                horizon: sky.horizon(),
+                zenith: sky.zenith(),
+                out_color: bundle.data.out_color.clone(),
+            },
+        };
+
         Overseer {
             window: window,
             device: device,
             factory: factory,
             encoder: encoder,
             bundle: bundle,
+            mesh_bundle: mesh_bundle,
+            transparent_bundle: transparent_bundle,
+            transparent_mesh_bundle: transparent_mesh_bundle,
+            sky_bundle: sky_bundle,
+            shadow_bundle: shadow_bundle,
+            shadow_mesh_bundle: shadow_mesh_bundle,
+            shadow: shadow,
+            shadow_maps: shadow_maps,
+            lights: lights,
+            light_params: light_params,
+            emissive_lights: 4,
+            emitter_lights: Vec::new(),
+            sky: sky,
+            sun: sun,
             camera: camera,
             world: world,
+            loader: world::loader::ChunkLoader::new(2, 64),
+            streamer: streamer,
+            observer: observer,
+            buffers: BufferManager::new(gfx::BufferRole::Vertex),
+            mesh_buffers: BufferManager::new(gfx::BufferRole::Vertex),
+            index_buffers: BufferManager::new(gfx::BufferRole::Index),
+            transparent_buffers: BufferManager::new(gfx::BufferRole::Vertex),
+            transparent: HashMap::new(),
+            transparent_mesh_buffers: BufferManager::new(gfx::BufferRole::Vertex),
+            transparent_index_buffers: BufferManager::new(gfx::BufferRole::Index),
+            transparent_meshes: HashMap::new(),
+            sorted_from: None,
+            meshed: true,
+            lod: lod,
+            levels: HashMap::new(),
+            visibility: VisibilityGraph::new(),
+            stats: RenderStats::default(),
         }
     }
 
     pub fn update(&mut self, delta: f32) {
         self.camera.update(&self.window);
 
-        let mut instances = Vec::new();
+        self.streamer.update(&mut self.world, &self.loader, self.camera.position);
+        self.world.integrate(&self.loader);
+        self.world.update_lighting();
+
+        // free the buffers of chunks that went away
+        for event in self.world.poll_events(self.observer) {
+            if let WorldEvent::ChunkUnloaded(position) = event {
+                self.buffers.remove(position);
+                self.mesh_buffers.remove(position);
+                self.index_buffers.remove(position);
+                self.transparent_buffers.remove(position);
+                self.transparent.remove(&position);
+                self.transparent_mesh_buffers.remove(position);
+                self.transparent_index_buffers.remove(position);
+                self.transparent_meshes.remove(&position);
+                self.levels.remove(&position);
+                self.visibility.remove(position);
+            }
+        }
+
+        // chunks that moved to another level are rebuilt along with the chunks around them,
+        // whose border faces and occlusion depend on whether the levels match
+        let center = world::stream::chunk_at(self.camera.position);
+        let mut moved = Vec::new();
         for chunk in self.world.chunks.iter() {
-            chunk.instances(&mut instances);
+            let position = chunk.position();
+            let level = self.lod.level(center, position);
+            if self.levels.insert(position, level) != Some(level) {
+                moved.push(position);
+            }
+        }
+
+        for position in moved {
+            if let Some(chunk) = self.world.chunk_mut(position) {
+                chunk.dirty.mesh = true;
+            }
+            for x in -1..2 {
+                for y in -1..2 {
+                    for z in -1..2 {
+                        if let Some(chunk) = self.world.chunk_mut([position[0] + x, position[1] + y, position[2] + z]) {
+                            chunk.dirty.mesh = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        // only upload the chunks that changed since last frame
+        let dirty = self.world.chunks.iter()
+            .filter(|chunk| chunk.dirty.mesh)
+            .map(|chunk| chunk.position())
+            .collect::<Vec<_>>();
+
+        for position in dirty {
+            if let Some(chunk) = self.world.chunk(position) {
+                self.visibility.update(chunk, &self.world.definitions);
+            }
+
+            let level = self.levels.get(&position).cloned().unwrap_or(0);
+
+            if level == 0 {
+                // chunks at another level count as empty so the seam is never left open
+                let mut around = self.world.around(position);
+                for x in -1..2 {
+                    for y in -1..2 {
+                        for z in -1..2 {
+                            let neighbour = [position[0] + x, position[1] + y, position[2] + z];
+                            if self.levels.get(&neighbour).map_or(false, |level| *level != 0) {
+                                around[world::chunk::around_index([x, y, z])] = None;
+                            }
+                        }
+                    }
+                }
+
+                let mut instances = Vec::new();
+                let mut transparent = Vec::new();
+                let mut mesh = world::mesh::Mesh::new();
+                if let Some(chunk) = self.world.chunk(position) {
+                    if self.meshed {
+                        mesh = world::mesh::lit(chunk, &around, &self.world.definitions);
+                        chunk.transparent_instances(&around, &self.world.definitions, &mut transparent);
+                    } else {
+                        chunk.instances(&around, &self.world.definitions, &mut instances, &mut transparent);
+                    }
+                }
+
+                if self.meshed {
+                    self.mesh_buffers.upload(&mut self.factory, &mut self.encoder, position, &mesh.vertices);
+                    self.index_buffers.upload(&mut self.factory, &mut self.encoder, position, &mesh.indices);
+                    self.buffers.remove(position);
+                } else {
+                    self.buffers.upload(&mut self.factory, &mut self.encoder, position, &instances);
+                    self.mesh_buffers.remove(position);
+                    self.index_buffers.remove(position);
+                }
+
+                if transparent.is_empty() {
+                    self.transparent_buffers.remove(position);
+                    self.transparent.remove(&position);
+                } else {
+                    sort_back_to_front(&mut transparent, self.camera.position.into());
+                    self.transparent_buffers.upload(&mut self.factory, &mut self.encoder, position, &transparent);
+                    self.transparent.insert(position, transparent);
+                }
+                self.transparent_mesh_buffers.remove(position);
+                self.transparent_index_buffers.remove(position);
+                self.transparent_meshes.remove(&position);
+            } else {
+                let (mesh, mut transparent) = match self.world.chunk(position) {
+                    Some(chunk) => world::lod::mesh(chunk, level, &self.world.definitions),
+                    None => (Mesh::new(), Mesh::new()),
+                };
+
+                self.mesh_buffers.upload(&mut self.factory, &mut self.encoder, position, &mesh.vertices);
+                self.index_buffers.upload(&mut self.factory, &mut self.encoder, position, &mesh.indices);
+                self.buffers.remove(position);
+                self.transparent_buffers.remove(position);
+                self.transparent.remove(&position);
+
+                if transparent.is_empty() {
+                    self.transparent_mesh_buffers.remove(position);
+                    self.transparent_index_buffers.remove(position);
+                    self.transparent_meshes.remove(&position);
+                } else {
+                    transparent.sort_back_to_front(voxel_eye(self.camera.position.into()));
+                    self.transparent_mesh_buffers.upload(&mut self.factory, &mut self.encoder, position, &transparent.vertices);
+                    self.transparent_index_buffers.upload(&mut self.factory, &mut self.encoder, position, &transparent.indices);
+                    self.transparent_meshes.insert(position, transparent);
+                }
+            }
+
+            if let Some(chunk) = self.world.chunk_mut(position) {
+                chunk.dirty.mesh = false;
+            }
+        }
+
+        // the order inside each chunk only needs redoing once the camera crosses into another chunk
+        if self.sorted_from != Some(center) {
+            let eye = self.camera.position.into();
+            for (position, transparent) in self.transparent.iter_mut() {
+                sort_back_to_front(transparent, eye);
+                self.transparent_buffers.upload(&mut self.factory, &mut self.encoder, *position, transparent);
+            }
+            for (position, transparent) in self.transparent_meshes.iter_mut() {
+                transparent.sort_back_to_front(voxel_eye(eye));
+                self.transparent_index_buffers.upload(&mut self.factory, &mut self.encoder, *position, &transparent.indices);
+            }
+            self.sorted_from = Some(center);
         }
 
-        self.encoder.update_buffer(&self.bundle.data.voxels, instances.as_slice(), 0);
+        self.sky.update(delta);
+        if let Some(sun) = self.sun {
+            let (direction, color, intensity) = (self.sky.sun_direction(), self.sky.sun_color(), self.sky.sun_intensity());
+            self.aim_light(sun, direction);
+            self.recolor_light(sun, color, intensity);
+        }
 
-        self.bundle.data.time += delta;
+        self.bundle.data.time = self.sky.time;
+        self.bundle.data.ambient = self.sky.ambient();
+        self.bundle.data.daylight = self.sky.sky_light();
         self.bundle.data.transform = (self.camera.perspective * self.camera.view).into();
+        self.sky_bundle.data.inverse = inverse(self.camera.perspective * self.camera.view);
+        self.sky_bundle.data.horizon = self.sky.horizon();
+        self.sky_bundle.data.zenith = self.sky.zenith();
+        self.mesh_bundle.data.time = self.bundle.data.time;
+        self.mesh_bundle.data.ambient = self.bundle.data.ambient;
+        self.mesh_bundle.data.daylight = self.bundle.data.daylight;
+        self.mesh_bundle.data.transform = self.bundle.data.transform;
+        self.transparent_bundle.data.time = self.bundle.data.time;
+        self.transparent_bundle.data.ambient = self.bundle.data.ambient;
+        self.transparent_bundle.data.daylight = self.bundle.data.daylight;
+        self.transparent_bundle.data.transform = self.bundle.data.transform;
+        self.transparent_mesh_bundle.data.time = self.bundle.data.time;
+        self.transparent_mesh_bundle.data.ambient = self.bundle.data.ambient;
+        self.transparent_mesh_bundle.data.daylight = self.bundle.data.daylight;
+        self.transparent_mesh_bundle.data.transform = self.bundle.data.transform;
+
+        self.bundle.data.shadow_bias = self.shadow.bias;
+        self.bundle.data.shadow_kernel = self.shadow.kernel;
+        self.mesh_bundle.data.shadow_bias = self.shadow.bias;
+        self.mesh_bundle.data.shadow_kernel = self.shadow.kernel;
+        self.transparent_bundle.data.shadow_bias = self.shadow.bias;
+        self.transparent_bundle.data.shadow_kernel = self.shadow.kernel;
+        self.transparent_mesh_bundle.data.shadow_bias = self.shadow.bias;
+        self.transparent_mesh_bundle.data.shadow_kernel = self.shadow.kernel;
+
+        self.light_emitters();
+
+        // directional shadows follow the camera, so the lights go up every frame
+        self.light_params = self.lights.params(self.camera.position.into());
+        if !self.light_params.is_empty() {
+            self.encoder.update_buffer(&self.bundle.data.lights, &self.light_params, 0);
+        }
+        self.bundle.data.light_count = self.light_params.len() as i32;
+        self.mesh_bundle.data.light_count = self.light_params.len() as i32;
+        self.transparent_bundle.data.light_count = self.light_params.len() as i32;
+        self.transparent_mesh_bundle.data.light_count = self.light_params.len() as i32;
+    }
+
+    // the emissive voxels closest to the camera light their surroundings from whichever
+    // slots are free, an emitter keeps its slot for as long as it stays among the closest
+    fn light_emitters(&mut self) {
+        let scale = world::chunk::SCALE;
+        let point = [self.camera.position.x / scale, self.camera.position.y / scale, self.camera.position.z / scale];
+        let near = self.world.emitters.near(point, self.emissive_lights.min(MAX_LIGHTS / 2));
+
+        // slots of emitters that are no longer close go back
+        let mut kept = Vec::new();
+        for (id, position) in self.emitter_lights.drain(..) {
+            if near.iter().any(|emitter| emitter.position == position) {
+                kept.push((id, position));
+            } else {
+                self.lights.remove(id);
+            }
+        }
+        self.emitter_lights = kept;
+
+        for emitter in near.iter() {
+            let color = match self.world.definition(emitter.id) {
+                Some(definition) => definition.emission_color(),
+                None => [1.0, 1.0, 1.0],
+            };
+
+            // reaches about `level` voxels before fading out
+            let reach = emitter.level as f32 * scale;
+            let position = [emitter.position[0] as f32 * scale, emitter.position[1] as f32 * scale, emitter.position[2] as f32 * scale];
+            let light = Light {
+                attenuation: [1.0, 0.0, 4.0 / (reach * reach)],
+                shadows: false,
+                .. Light::point(position, color, emitter.level as f32 / 15.0)
+            };
+
+            // updated in place, the emitter's voxel may have changed since last frame
+            let lent = self.emitter_lights.iter().find(|slot| slot.1 == emitter.position).map(|slot| slot.0);
+            match lent {
+                Some(id) => {
+                    if let Some(slot) = self.lights.get_mut(id) {
+                        *slot = light;
+                    }
+                },
+                None => match self.lights.add(light) {
+                    Some(id) => self.emitter_lights.push((id, emitter.position)),
+                    None => break,
+                },
+            }
+        }
+    }
+
+    // None once MAX_LIGHTS lights were added, emissive voxels give up their slots to make room
+    pub fn add_light(&mut self, light: Light) -> Option<LightId> {
+        if let Some(id) = self.lights.add(light) {
+            return Some(id);
+        }
+
+        // the emitter lights are only lent, the one farthest from the camera goes first
+        let scale = world::chunk::SCALE;
+        let eye = [self.camera.position.x, self.camera.position.y, self.camera.position.z];
+        let mut farthest: Option<(usize, f32)> = None;
+        for (index, &(_, position)) in self.emitter_lights.iter().enumerate() {
+            let d = distance(eye, [position[0] as f32 * scale, position[1] as f32 * scale, position[2] as f32 * scale]);
+            if farthest.map_or(true, |(_, most)| d > most) {
+                farthest = Some((index, d));
+            }
+        }
+
+        match farthest {
+            Some((index, _)) => {
+                let (id, _) = self.emitter_lights.remove(index);
+                self.lights.remove(id);
+                self.lights.add(light)
+            },
+            None => None,
+        }
+    }
+
+    pub fn move_light(&mut self, id: LightId, position: [f32; 3]) {
+        if let Some(light) = self.lights.get_mut(id) {
+            light.position = position;
+        }
+    }
+
+    pub fn aim_light(&mut self, id: LightId, direction: [f32; 3]) {
+        if let Some(light) = self.lights.get_mut(id) {
+            light.direction = direction;
+        }
+    }
+
+    pub fn recolor_light(&mut self, id: LightId, color: [f32; 3], intensity: f32) {
+        if let Some(light) = self.lights.get_mut(id) {
+            light.color = color;
+            light.intensity = intensity;
+        }
+    }
+
+    pub fn remove_light(&mut self, id: LightId) -> Option<Light> {
+        self.emitter_lights.retain(|&(lent, _)| lent != id);
+        self.lights.remove(id)
+    }
+
+    // remakes the shadow maps at a new size
+    pub fn set_shadow_resolution(&mut self, resolution: u16) {
+        self.shadow.resolution = resolution;
+        self.shadow_maps = ShadowMaps::new(&mut self.factory, resolution, MAX_LIGHTS);
+
+        let resource = (self.shadow_maps.resource.clone(), self.shadow_maps.sampler.clone());
+        self.bundle.data.shadow = resource.clone();
+        self.mesh_bundle.data.shadow = resource.clone();
+        self.transparent_bundle.data.shadow = resource.clone();
+        self.transparent_mesh_bundle.data.shadow = resource;
+    }
+
+    // depth of everything loaded as seen from each light, into that light's layer
+    fn render_shadows(&mut self) {
+        let shadowed = self.lights.active().iter().map(|light| light.shadows).collect::<Vec<_>>();
+        for ((light, target), shadowed) in self.light_params.iter().zip(self.shadow_maps.targets.iter()).zip(shadowed) {
+            if !shadowed {
+                continue;
+            }
+
+            self.encoder.clear_depth(target, 1.0);
+
+            self.shadow_bundle.data.transform = light.proj;
+            self.shadow_bundle.data.out_depth = target.clone();
+            for (_, buffer) in self.buffers.iter() {
+                if buffer.count == 0 {
+                    continue;
+                }
+
+                self.shadow_bundle.data.voxels = buffer.buffer.clone();
+                self.shadow_bundle.slice.instances = Some((buffer.count, 0));
+                self.shadow_bundle.encode(&mut self.encoder);
+            }
+
+            self.shadow_mesh_bundle.data.transform = light.proj;
+            self.shadow_mesh_bundle.data.out_depth = target.clone();
+            for (position, indices) in self.index_buffers.iter() {
+                let vertices = match self.mesh_buffers.get(*position) {
+                    Some(vertices) => vertices,
+                    None => continue,
+                };
+                if indices.count == 0 {
+                    continue;
+                }
+
+                self.shadow_mesh_bundle.data.vbuf = vertices.buffer.clone();
+                self.shadow_mesh_bundle.slice.buffer = gfx::IndexBuffer::Index16(indices.buffer.clone());
+                self.shadow_mesh_bundle.slice.end = indices.count;
+                self.shadow_mesh_bundle.encode(&mut self.encoder);
+            }
+        }
     }
 
     pub fn render(&mut self) {
-        self.encoder.clear(&self.bundle.data.out_color, [0.1, 0.2, 0.3, 1.0]);
+        self.render_shadows();
+
+        // the sky behind everything, drawn over the whole screen first
         self.encoder.clear_depth(&self.bundle.data.out_depth, 1.0);
-        self.bundle.encode(&mut self.encoder);
+        self.sky_bundle.encode(&mut self.encoder);
+
+        let frustum = self.camera.frustum();
+        let center = world::stream::chunk_at(self.camera.position);
+        let visible = self.visibility.visible(center, frustum.as_ref());
+        self.stats = RenderStats::default();
+
+        // one draw per chunk, each with its own instance buffer and current count
+        for (position, buffer) in self.buffers.iter() {
+            if buffer.count == 0 || culled(&frustum, &visible, *position, &mut self.stats) {
+                continue;
+            }
+
+            self.stats.drawn += 1;
+            self.stats.instances += buffer.count as usize;
+
+            self.bundle.data.voxels = buffer.buffer.clone();
+            self.bundle.slice.instances = Some((buffer.count, 0));
+            self.bundle.encode(&mut self.encoder);
+        }
+
+        for (position, indices) in self.index_buffers.iter() {
+            let vertices = match self.mesh_buffers.get(*position) {
+                Some(vertices) => vertices,
+                None => continue,
+            };
+            if indices.count == 0 || culled(&frustum, &visible, *position, &mut self.stats) {
+                continue;
+            }
+
+            self.stats.drawn += 1;
+            self.stats.triangles += indices.count as usize / 3;
+
+            self.mesh_bundle.data.vbuf = vertices.buffer.clone();
+            self.mesh_bundle.slice.buffer = gfx::IndexBuffer::Index16(indices.buffer.clone());
+            self.mesh_bundle.slice.end = indices.count;
+            self.mesh_bundle.encode(&mut self.encoder);
+        }
+
+        // transparent voxels and coarse cells last, farthest chunk first
+        let eye = self.camera.position.into();
+        let mut transparent = Vec::new();
+        let positions = self.transparent_buffers.iter().map(|(position, buffer)| (*position, buffer.count, false))
+            .chain(self.transparent_index_buffers.iter().map(|(position, indices)| (*position, indices.count, true)));
+        for (position, count, meshed) in positions {
+            if count == 0 || culled(&frustum, &visible, position, &mut self.stats) {
+                continue;
+            }
+
+            let bounds = world::chunk::bounds(position);
+            let center = [(bounds.min.x + bounds.max.x) * 0.5, (bounds.min.y + bounds.max.y) * 0.5, (bounds.min.z + bounds.max.z) * 0.5];
+            transparent.push((distance(eye, center), position, meshed));
+        }
+        transparent.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+        for (_, position, meshed) in transparent {
+            if meshed {
+                let (vertices, indices) = match (self.transparent_mesh_buffers.get(position), self.transparent_index_buffers.get(position)) {
+                    (Some(vertices), Some(indices)) => (vertices, indices),
+                    _ => continue,
+                };
+                self.stats.triangles += indices.count as usize / 3;
+
+                self.transparent_mesh_bundle.data.vbuf = vertices.buffer.clone();
+                self.transparent_mesh_bundle.slice.buffer = gfx::IndexBuffer::Index16(indices.buffer.clone());
+                self.transparent_mesh_bundle.slice.end = indices.count;
+                self.transparent_mesh_bundle.encode(&mut self.encoder);
+            } else if let Some(buffer) = self.transparent_buffers.get(position) {
+                self.stats.instances += buffer.count as usize;
+
+                self.transparent_bundle.data.voxels = buffer.buffer.clone();
+                self.transparent_bundle.slice.instances = Some((buffer.count, 0));
+                self.transparent_bundle.encode(&mut self.encoder);
+            }
+        }
+
         self.encoder.flush(&mut self.device);
         self.window.swap_buffers().unwrap();
         self.device.cleanup();
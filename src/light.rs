@@ -0,0 +1,191 @@
+use cgmath::{self, Matrix4, Point3, Vector3};
+use cgmath::prelude::InnerSpace;
+
+use super::LightParam;
+
+// matches MAX_LIGHTS in voxel.glslf
+pub const MAX_LIGHTS: usize = 10;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightKind {
+    Point,
+    Spot {
+        inner: f32, // radians from the direction where the light starts fading
+        outer: f32, // radians from the direction where it's gone
+    },
+    Directional, // infinitely far along -direction, like the sun
+}
+
+impl LightKind {
+    // stored in the w of the position so the shader can tell them apart
+    fn tag(&self) -> f32 {
+        match *self {
+            LightKind::Directional => 0.0,
+            LightKind::Point => 1.0,
+            LightKind::Spot { .. } => 2.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: [f32; 3], // render units
+    pub direction: [f32; 3], // where spots and the sun point, and their shadows are cast towards
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub attenuation: [f32; 3], // constant, linear and quadratic falloff with distance
+    pub shadows: bool, // whether it gets a shadow map
+}
+
+impl Light {
+    pub fn point(position: [f32; 3], color: [f32; 3], intensity: f32) -> Light {
+        Light {
+            kind: LightKind::Point,
+            position: position,
+            direction: [0.0, -1.0, 0.0], // point lights shine every way, see `projection`
+            color: color,
+            intensity: intensity,
+            attenuation: [1.0, 0.0, 0.01],
+            shadows: true,
+        }
+    }
+
+    pub fn spot(position: [f32; 3], direction: [f32; 3], angle: f32, color: [f32; 3], intensity: f32) -> Light {
+        Light {
+            kind: LightKind::Spot { inner: angle * 0.8, outer: angle },
+            direction: direction,
+            .. Light::point(position, color, intensity)
+        }
+    }
+
+    pub fn directional(direction: [f32; 3], color: [f32; 3], intensity: f32) -> Light {
+        Light {
+            kind: LightKind::Directional,
+            position: [0.0, 0.0, 0.0],
+            direction: direction,
+            color: color,
+            intensity: intensity,
+            attenuation: [1.0, 0.0, 0.0],
+            shadows: true,
+        }
+    }
+
+    // what the light's shadow map sees, directional lights are centered on `focus` and
+    // point lights look towards it, since one map can't cover every way at once
+    pub fn projection(&self, focus: [f32; 3]) -> Matrix4<f32> {
+        let direction = match self.kind {
+            LightKind::Point => Vector3::new(focus[0] - self.position[0], focus[1] - self.position[1], focus[2] - self.position[2]),
+            _ => Vector3::new(self.direction[0], self.direction[1], self.direction[2]),
+        };
+        let direction = if direction.magnitude2() > 0.0 { direction.normalize() } else { -Vector3::unit_y() };
+        let up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+
+        match self.kind {
+            LightKind::Directional => {
+                let center = Point3::new(focus[0], focus[1], focus[2]);
+                let view = Matrix4::look_at(center - direction * 64.0, center, up);
+                cgmath::ortho(-32.0, 32.0, -32.0, 32.0, 1.0, 128.0) * view
+            },
+            _ => {
+                let fov = match self.kind {
+                    LightKind::Spot { outer, .. } => (outer * 2.0).to_degrees().min(170.0),
+                    _ => 90.0,
+                };
+
+                let eye = Point3::new(self.position[0], self.position[1], self.position[2]);
+                let view = Matrix4::look_at(eye, eye + direction, up);
+                cgmath::perspective(cgmath::deg(fov), 1.0, 0.5, 64.0) * view
+            },
+        }
+    }
+
+    pub fn param(&self, focus: [f32; 3]) -> LightParam {
+        let (inner, outer) = match self.kind {
+            LightKind::Spot { inner, outer } => (inner.cos(), outer.cos()),
+            _ => (-1.0, -1.0),
+        };
+
+        LightParam {
+            pos: [self.position[0], self.position[1], self.position[2], self.kind.tag()],
+            color: [self.color[0], self.color[1], self.color[2], self.intensity],
+            direction: [self.direction[0], self.direction[1], self.direction[2], outer],
+            attenuation: [self.attenuation[0], self.attenuation[1], self.attenuation[2], inner],
+            // an empty projection puts everything outside the shadow map, so it's never shadowed
+            proj: if self.shadows { self.projection(focus).into() } else { [[0.0; 4]; 4] },
+        }
+    }
+}
+
+pub type LightId = usize;
+
+// up to MAX_LIGHTS lights, ids stay valid until the light is removed
+#[derive(Clone, Debug)]
+pub struct Lights {
+    slots: Vec<Option<Light>>,
+}
+
+impl Lights {
+    pub fn new() -> Lights {
+        Lights {
+            slots: Vec::new(),
+        }
+    }
+
+    // None once every slot is taken
+    pub fn add(&mut self, light: Light) -> Option<LightId> {
+        // reuse the slot of a light that was removed
+        if let Some(id) = self.slots.iter().position(|slot| slot.is_none()) {
+            self.slots[id] = Some(light);
+            return Some(id);
+        }
+
+        if self.slots.len() >= MAX_LIGHTS {
+            return None;
+        }
+
+        self.slots.push(Some(light));
+        Some(self.slots.len() - 1)
+    }
+
+    pub fn remove(&mut self, id: LightId) -> Option<Light> {
+        match self.slots.get_mut(id) {
+            Some(slot) => slot.take(),
+            None => None,
+        }
+    }
+
+    pub fn get(&self, id: LightId) -> Option<&Light> {
+        match self.slots.get(id) {
+            Some(&Some(ref light)) => Some(light),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, id: LightId) -> Option<&mut Light> {
+        match self.slots.get_mut(id) {
+            Some(&mut Some(ref mut light)) => Some(light),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    // the lights giving off any light in slot order, matching `params`, so a dark one
+    // like the sun at night costs no shading or shadow map
+    pub fn active(&self) -> Vec<&Light> {
+        self.slots.iter()
+            .filter_map(|slot| slot.as_ref())
+            .filter(|light| light.intensity > 0.0)
+            .collect()
+    }
+
+    // packed for b_Lights, in slot order
+    pub fn params(&self, focus: [f32; 3]) -> Vec<LightParam> {
+        self.active().into_iter()
+            .map(|light| light.param(focus))
+            .collect()
+    }
+}
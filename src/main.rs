@@ -68,6 +68,19 @@ fn main() {
             av /= average.len() as f64;
 
             println!("fps: {:?}", av as u32);
+
+            let memory = overseer.world.memory_usage();
+            println!("chunks: {} kB loaded, {} kB cached, {} kB budget",
+                     memory.loaded / 1024, memory.cached / 1024, memory.budget / 1024);
+
+            let stats = overseer.stats;
+            println!("chunks: {} drawn, {} culled, {} occluded, {} instances, {} lod triangles",
+                     stats.drawn, stats.culled, stats.occluded, stats.instances, stats.triangles);
+
+            let buffers = overseer.buffers.utilisation();
+            println!("buffers: {} chunks, {}/{} instances ({:.0}%), {} kB, {} grown",
+                     buffers.buffers, buffers.used, buffers.capacity, buffers.ratio() * 100.0,
+                     buffers.bytes / 1024, buffers.grown);
             count = 0.0f64;
         }
 
@@ -87,7 +100,9 @@ fn main() {
             overseer.window.set_cursor_state(glutin::CursorState::Normal).unwrap();
         }
 
-        overseer.world.chunks[0].raycast_voxel(&overseer.camera, 0, 0, 0);
+        if let Some(chunk) = overseer.world.chunks.get_mut(0) {
+            chunk.raycast_voxel(&overseer.camera, 0, 0, 0);
+        }
 
         overseer.update(dt32);
         overseer.render();
@@ -108,6 +123,13 @@ fn main() {
                     gfx_window_glutin::update_views(&overseer.window,
                                                     &mut overseer.bundle.data.out_color,
                                                     &mut overseer.bundle.data.out_depth);
+                    overseer.mesh_bundle.data.out_color = overseer.bundle.data.out_color.clone();
+                    overseer.mesh_bundle.data.out_depth = overseer.bundle.data.out_depth.clone();
+                    overseer.transparent_bundle.data.out_color = overseer.bundle.data.out_color.clone();
+                    overseer.transparent_bundle.data.out_depth = overseer.bundle.data.out_depth.clone();
+                    overseer.transparent_mesh_bundle.data.out_color = overseer.bundle.data.out_color.clone();
+                    overseer.transparent_mesh_bundle.data.out_depth = overseer.bundle.data.out_depth.clone();
+                    overseer.sky_bundle.data.out_color = overseer.bundle.data.out_color.clone();
                 },
 
                 Event::MouseMoved(x, y) => {
@@ -0,0 +1,77 @@
+use gfx;
+use gfx::traits::Factory;
+use gfx_device_gl;
+
+use super::Vertex;
+use world::chunk::InstancedVoxel;
+use world::mesh::MeshVertex;
+
+pub type ShadowFormat = gfx::format::Depth;
+
+// depth of the voxels seen from a light
+gfx_pipeline!( shadow_pipe {
+    vbuf: gfx::VertexBuffer<Vertex> = (),
+    transform: gfx::Global<[[f32; 4]; 4]> = "c_Transform",
+    voxels: gfx::InstanceBuffer<InstancedVoxel> = (),
+    out_depth: gfx::DepthTarget<ShadowFormat> =
+        gfx::preset::depth::LESS_EQUAL_WRITE,
+});
+
+gfx_pipeline!( shadow_mesh_pipe {
+    vbuf: gfx::VertexBuffer<MeshVertex> = (),
+    transform: gfx::Global<[[f32; 4]; 4]> = "c_Transform",
+    out_depth: gfx::DepthTarget<ShadowFormat> =
+        gfx::preset::depth::LESS_EQUAL_WRITE,
+});
+
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowSettings {
+    pub resolution: u16, // texels per side of each light's map
+    pub bias: f32, // depth subtracted before comparing, against shadow acne
+    pub kernel: i32, // pcf samples reach this many texels each way
+}
+
+impl ShadowSettings {
+    pub fn new() -> ShadowSettings {
+        ShadowSettings {
+            resolution: 1024,
+            bias: 0.005,
+            kernel: 1,
+        }
+    }
+}
+
+// one depth layer per light, rendered into as targets and sampled as a texture array
+pub struct ShadowMaps {
+    pub targets: Vec<gfx::handle::DepthStencilView<gfx_device_gl::Resources, ShadowFormat>>,
+    pub resource: gfx::handle::ShaderResourceView<gfx_device_gl::Resources, f32>,
+    pub sampler: gfx::handle::Sampler<gfx_device_gl::Resources>,
+}
+
+impl ShadowMaps {
+    pub fn new(factory: &mut gfx_device_gl::Factory, resolution: u16, layers: usize) -> ShadowMaps {
+        // empty texture arrays can't be created
+        let layers = if layers == 0 { 1 } else { layers };
+
+        let kind = gfx::tex::Kind::D2Array(resolution, resolution, layers as gfx::Layer, gfx::tex::AaMode::Single);
+        let texture = factory.create_texture(kind, 1, gfx::SHADER_RESOURCE | gfx::DEPTH_STENCIL,
+                                             gfx::Usage::GpuOnly, Some(gfx::format::ChannelType::Unorm)).unwrap();
+
+        let resource = factory.view_texture_as_shader_resource::<ShadowFormat>(&texture, (0, 0), gfx::format::Swizzle::new()).unwrap();
+
+        let targets = (0..layers).map(|layer| {
+            factory.view_texture_as_depth_stencil(&texture, 0, Some(layer as gfx::Layer), gfx::tex::DepthStencilFlags::empty()).unwrap()
+        }).collect();
+
+        // compares in the sampler so the shader gets filtered visibility back
+        let mut info = gfx::tex::SamplerInfo::new(gfx::tex::FilterMethod::Bilinear, gfx::tex::WrapMode::Clamp);
+        info.comparison = Some(gfx::state::Comparison::LessEqual);
+        let sampler = factory.create_sampler(info);
+
+        ShadowMaps {
+            targets: targets,
+            resource: resource,
+            sampler: sampler,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::f32::consts::PI;
+
+use gfx;
+
+use super::ColorFormat;
+
+// sky colours at midnight, noon and around sunrise and sunset
+const NIGHT_ZENITH: [f32; 3] = [0.01, 0.01, 0.04];
+const NIGHT_HORIZON: [f32; 3] = [0.03, 0.04, 0.08];
+const DAY_ZENITH: [f32; 3] = [0.25, 0.45, 0.85];
+const DAY_HORIZON: [f32; 3] = [0.65, 0.78, 0.92];
+const DUSK_HORIZON: [f32; 3] = [0.9, 0.45, 0.2];
+
+// how far the sun's path leans towards +z, so it's never straight overhead
+const TILT: f32 = 0.3;
+
+gfx_vertex_struct!( SkyVertex {
+    pos: [f32; 2] = "a_Pos",
+});
+
+// one triangle past every edge of the screen
+pub const SCREEN: [SkyVertex; 3] = [
+    SkyVertex { pos: [-1.0, -1.0] },
+    SkyVertex { pos: [3.0, -1.0] },
+    SkyVertex { pos: [-1.0, 3.0] },
+];
+
+// the gradient behind everything, worked out per pixel from where it looks
+gfx_pipeline!( sky_pipe {
+    vbuf: gfx::VertexBuffer<SkyVertex> = (),
+    inverse: gfx::Global<[[f32; 4]; 4]> = "u_Inverse",
+    horizon: gfx::Global<[f32; 3]> = "u_Horizon",
+    zenith: gfx::Global<[f32; 3]> = "u_Zenith",
+    out_color: gfx::RenderTarget<ColorFormat> = "Target0",
+});
+
+// time of day and everything derived from it
+#[derive(Copy, Clone, Debug)]
+pub struct Sky {
+    pub day_length: f32, // seconds from one midnight to the next
+    pub time: f32, // fraction of the day, 0 is midnight, 0.25 sunrise, 0.5 noon and 0.75 sunset
+    pub frozen: bool, // whether time stands still
+}
+
+impl Sky {
+    pub fn new(day_length: f32) -> Sky {
+        Sky {
+            day_length: day_length,
+            time: 0.3,
+            frozen: false,
+        }
+    }
+
+    pub fn update(&mut self, delta: f32) {
+        if !self.frozen && self.day_length > 0.0 {
+            let time = self.time + delta / self.day_length;
+            self.set_time(time);
+        }
+    }
+
+    // wraps around, so 1.25 is the same as 0.25
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time - time.floor();
+    }
+
+    pub fn freeze(&mut self, frozen: bool) {
+        self.frozen = frozen;
+    }
+
+    // unit vector towards the sun, it rises in +x and sets in -x
+    pub fn sun(&self) -> [f32; 3] {
+        let (sin, cos) = ((self.time - 0.25) * 2.0 * PI).sin_cos();
+        let length = (1.0 + TILT * TILT).sqrt();
+        [cos / length, sin / length, TILT / length]
+    }
+
+    // where sunlight travels, for the directional light
+    pub fn sun_direction(&self) -> [f32; 3] {
+        let sun = self.sun();
+        [-sun[0], -sun[1], -sun[2]]
+    }
+
+    // 0 at night and 1 during the day
+    pub fn daylight(&self) -> f32 {
+        smoothstep(-0.1, 0.2, self.sun()[1])
+    }
+
+    // how much of the flood filled sky light reaches the voxels, moonlight keeps
+    // some of it at night
+    pub fn sky_light(&self) -> f32 {
+        0.15 + 0.85 * self.daylight()
+    }
+
+    // how much the horizon glows while the sun is close to it
+    fn dusk(&self) -> f32 {
+        (1.0 - self.sun()[1].abs() / 0.25).max(0.0)
+    }
+
+    // the top of the gradient in sky.glslf
+    pub fn zenith(&self) -> [f32; 3] {
+        mix(NIGHT_ZENITH, DAY_ZENITH, self.daylight())
+    }
+
+    // and the bottom, also what's below the horizon
+    pub fn horizon(&self) -> [f32; 3] {
+        let horizon = mix(NIGHT_HORIZON, DAY_HORIZON, self.daylight());
+        mix(horizon, DUSK_HORIZON, self.dusk() * 0.7)
+    }
+
+    // light from the whole sky, tinted by it
+    pub fn ambient(&self) -> [f32; 3] {
+        let zenith = self.zenith();
+        [0.03 + zenith[0] * 0.2, 0.03 + zenith[1] * 0.2, 0.03 + zenith[2] * 0.2]
+    }
+
+    // reddens as the sun gets low
+    pub fn sun_color(&self) -> [f32; 3] {
+        mix([1.0, 0.55, 0.3], [1.0, 0.97, 0.9], smoothstep(0.0, 0.4, self.sun()[1]))
+    }
+
+    // gone once the sun is below the horizon
+    pub fn sun_intensity(&self) -> f32 {
+        smoothstep(-0.05, 0.1, self.sun()[1]) * 0.8
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+fn mix(a: [f32; 3], b: [f32; 3], t: f32) -> [f32; 3] {
+    [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sky;
+
+    fn length(v: [f32; 3]) -> f32 {
+        (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+    }
+
+    #[test]
+    fn set_time_wraps() {
+        let mut sky = Sky::new(600.0);
+
+        sky.set_time(1.25);
+        assert!((sky.time - 0.25).abs() < 1e-6);
+
+        sky.set_time(-0.25);
+        assert!((sky.time - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sun_is_a_unit_vector() {
+        let mut sky = Sky::new(600.0);
+        for i in 0..24 {
+            sky.set_time(i as f32 / 24.0);
+            assert!((length(sky.sun()) - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn sun_is_up_at_noon_and_down_at_midnight() {
+        let mut sky = Sky::new(600.0);
+
+        sky.set_time(0.5);
+        assert!(sky.sun()[1] > 0.9);
+        assert_eq!(sky.daylight(), 1.0);
+
+        sky.set_time(0.0);
+        assert!(sky.sun()[1] < -0.9);
+        assert_eq!(sky.daylight(), 0.0);
+        assert_eq!(sky.sun_intensity(), 0.0);
+    }
+
+    #[test]
+    fn update_follows_the_day_length() {
+        let mut sky = Sky::new(600.0);
+        sky.set_time(0.0);
+
+        sky.update(150.0);
+        assert!((sky.time - 0.25).abs() < 1e-6);
+
+        // a whole day comes back around
+        sky.update(600.0);
+        assert!((sky.time - 0.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn freeze_stops_update() {
+        let mut sky = Sky::new(600.0);
+        let time = sky.time;
+
+        sky.freeze(true);
+        sky.update(100.0);
+        assert_eq!(sky.time, time);
+
+        sky.freeze(false);
+        sky.update(60.0);
+        assert!((sky.time - (time + 0.1)).abs() < 1e-6);
+    }
+}
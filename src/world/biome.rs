@@ -0,0 +1,296 @@
+use std::sync::Arc;
+
+use rand::Rng;
+
+use super::World;
+use super::chunk::{Chunk, SIZE};
+use super::generator::ChunkGenerator;
+use super::noise::{self, Noise};
+use super::pipeline::{Region, Stage, Status};
+use super::prefab::Prefab;
+
+#[derive(Clone, Debug)]
+pub struct Biome {
+    pub name: String,
+
+    pub surface: u16, // voxel ids
+    pub subsurface: u16,
+    pub depth: i32, // subsurface voxels under the surface
+
+    pub height: f32, // average surface height in voxels
+    pub amplitude: f32, // how far the surface strays from `height`
+
+    pub temperature: f32, // 0..1, where the biome sits in the climate map
+    pub moisture: f32,
+
+    pub prefabs: Vec<Prefab>,
+    pub decorations: u32, // prefab attempts per chunk
+}
+
+impl Biome {
+    pub fn new(name: &str, surface: u16, subsurface: u16) -> Biome {
+        Biome {
+            name: name.to_owned(),
+
+            surface: surface,
+            subsurface: subsurface,
+            depth: 3,
+
+            height: 0.0,
+            amplitude: 8.0,
+
+            temperature: 0.5,
+            moisture: 0.5,
+
+            prefabs: Vec::new(),
+            decorations: 0,
+        }
+    }
+
+    // looks the materials up by their names in the wdfn
+    pub fn named(world: &World, name: &str, surface: &str, subsurface: &str) -> Option<Biome> {
+        match (world.id(surface), world.id(subsurface)) {
+            (Some(surface), Some(subsurface)) => Some(Biome::new(name, surface, subsurface)),
+            _ => {
+                println!("Biome {:?} uses undefined materials {:?}, {:?}", name, surface, subsurface);
+                None
+            },
+        }
+    }
+
+    pub fn climate(mut self, temperature: f32, moisture: f32) -> Biome {
+        self.temperature = temperature;
+        self.moisture = moisture;
+        self
+    }
+
+    pub fn profile(mut self, height: f32, amplitude: f32) -> Biome {
+        self.height = height;
+        self.amplitude = amplitude;
+        self
+    }
+
+    pub fn decorate(mut self, prefab: Prefab, attempts: u32) -> Biome {
+        self.prefabs.push(prefab);
+        self.decorations = attempts;
+        self
+    }
+}
+
+// picks biomes per world column from temperature and moisture noise
+#[derive(Debug)]
+pub struct BiomeMap {
+    seed: u64,
+    temperature: Noise,
+    moisture: Noise,
+    terrain: Noise,
+
+    pub biomes: Vec<Biome>,
+    pub scale: f32, // voxels across one climate noise cell
+    pub terrain_scale: f32, // voxels across one terrain noise cell
+    pub blend: f32, // higher gives sharper borders between biomes
+}
+
+impl BiomeMap {
+    pub fn new(seed: u64) -> BiomeMap {
+        BiomeMap {
+            seed: seed,
+            temperature: Noise::new(seed ^ 0x7e39),
+            moisture: Noise::new(seed ^ 0x3015),
+            terrain: Noise::new(seed),
+
+            biomes: Vec::new(),
+            scale: 256.0,
+            terrain_scale: 64.0,
+            blend: 8.0,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn add(&mut self, biome: Biome) {
+        self.biomes.push(biome);
+    }
+
+    // temperature and moisture of a column, both 0..1
+    pub fn climate(&self, x: i32, z: i32) -> (f32, f32) {
+        let (x, z) = (x as f32 / self.scale, z as f32 / self.scale);
+        let temperature = self.temperature.fractal2(x, z, 3, 0.5) * 0.5 + 0.5;
+        let moisture = self.moisture.fractal2(x, z, 3, 0.5) * 0.5 + 0.5;
+        (temperature, moisture)
+    }
+
+    // how much each biome contributes to a column, summing to 1
+    pub fn weights(&self, x: i32, z: i32) -> Vec<f32> {
+        let (temperature, moisture) = self.climate(x, z);
+
+        let mut weights = self.biomes.iter().map(|biome| {
+            let (dt, dm) = (biome.temperature - temperature, biome.moisture - moisture);
+            let distance = (dt * dt + dm * dm).sqrt();
+            1.0 / (distance + 0.001).powf(self.blend)
+        }).collect::<Vec<f32>>();
+
+        let total = weights.iter().fold(0.0, |sum, w| sum + w);
+        for weight in weights.iter_mut() {
+            *weight /= total;
+        }
+
+        weights
+    }
+
+    // the biome with the most say over a column
+    pub fn biome_at(&self, x: i32, z: i32) -> Option<&Biome> {
+        let weights = self.weights(x, z);
+        let mut best: Option<(usize, f32)> = None;
+
+        for (index, &weight) in weights.iter().enumerate() {
+            match best {
+                Some((_, b)) if b >= weight => { },
+                _ => best = Some((index, weight)),
+            }
+        }
+
+        best.map(|(index, _)| &self.biomes[index])
+    }
+
+    // surface height blended between the biomes' profiles
+    pub fn height_at(&self, x: i32, z: i32) -> i32 {
+        let n = self.terrain.fractal2(x as f32 / self.terrain_scale, z as f32 / self.terrain_scale, 4, 0.5);
+
+        let height = self.weights(x, z).iter()
+            .zip(self.biomes.iter())
+            .fold(0.0, |sum, (weight, biome)| sum + weight * (biome.height + n * biome.amplitude));
+
+        height.floor() as i32
+    }
+
+    // biome whose materials a column uses, dithered near borders so they mix
+    pub fn material_biome(&self, x: i32, z: i32) -> Option<&Biome> {
+        let weights = self.weights(x, z);
+        let mut pick = (noise::hash(self.seed, [x, 0, z]) % 1024) as f32 / 1024.0;
+
+        for (index, weight) in weights.iter().enumerate() {
+            if pick < *weight {
+                return Some(&self.biomes[index]);
+            }
+            pick -= *weight;
+        }
+
+        self.biomes.last()
+    }
+}
+
+// base terrain shaped by a biome map
+#[derive(Debug)]
+pub struct BiomeGenerator {
+    pub map: Arc<BiomeMap>,
+    pub stone: u16,
+}
+
+impl BiomeGenerator {
+    pub fn new(map: Arc<BiomeMap>, stone: u16) -> BiomeGenerator {
+        BiomeGenerator {
+            map: map,
+            stone: stone,
+        }
+    }
+}
+
+impl ChunkGenerator for BiomeGenerator {
+    fn generate(&self, position: [i32; 3]) -> Chunk {
+        let mut chunk = Chunk::new(position);
+        let size = SIZE as i32;
+
+        for x in 0..SIZE {
+            for z in 0..SIZE {
+                let (world_x, world_z) = (position[0] * size + x as i32, position[2] * size + z as i32);
+                let height = self.map.height_at(world_x, world_z);
+
+                let (surface, subsurface, depth) = match self.map.material_biome(world_x, world_z) {
+                    Some(biome) => (biome.surface, biome.subsurface, biome.depth),
+                    None => (self.stone, self.stone, 0),
+                };
+
+                for y in 0..SIZE {
+                    let world_y = position[1] * size + y as i32;
+
+                    let id = if world_y > height {
+                        0
+                    } else if world_y == height {
+                        surface
+                    } else if world_y > height - depth {
+                        subsurface
+                    } else {
+                        self.stone
+                    };
+
+                    if id != 0 {
+                        chunk.set([x, y, z], id);
+                    }
+                }
+            }
+        }
+
+        chunk.dirty.save = false;
+        chunk
+    }
+}
+
+// places each biome's prefabs on its own surface
+#[derive(Debug)]
+pub struct BiomeDecorationStage {
+    pub map: Arc<BiomeMap>,
+}
+
+impl BiomeDecorationStage {
+    pub fn new(map: Arc<BiomeMap>) -> BiomeDecorationStage {
+        BiomeDecorationStage {
+            map: map,
+        }
+    }
+}
+
+impl Stage for BiomeDecorationStage {
+    fn status(&self) -> Status {
+        Status::Decorated
+    }
+
+    fn reach(&self) -> bool {
+        true
+    }
+
+    fn run(&self, seed: u64, region: &mut Region) {
+        let mut rng = noise::rng(noise::hash(seed ^ 0xb10e, region.center()));
+        let origin = region.origin();
+
+        // the biome in the middle of the chunk decides how much gets placed
+        let middle = SIZE as i32 / 2;
+        let attempts = match self.map.biome_at(origin[0] + middle, origin[2] + middle) {
+            Some(biome) => biome.decorations,
+            None => 0,
+        };
+
+        for _ in 0..attempts {
+            let (x, z) = (rng.gen_range(0, SIZE), rng.gen_range(0, SIZE));
+            let (world_x, world_z) = (origin[0] + x as i32, origin[2] + z as i32);
+
+            let biome = match self.map.biome_at(world_x, world_z) {
+                Some(biome) if !biome.prefabs.is_empty() => biome,
+                _ => continue,
+            };
+            let prefab = &biome.prefabs[rng.gen_range(0, biome.prefabs.len())];
+
+            for y in (0..SIZE).rev() {
+                let position = [world_x, origin[1] + y as i32, world_z];
+                let above = [world_x, position[1] + 1, world_z];
+
+                if region.get(position) == Some(biome.surface) && region.get(above) == Some(0) {
+                    prefab.place(region, above);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::mem;
+use std::collections::HashMap;
+
+use super::chunk::Chunk;
+
+#[derive(Debug)]
+struct Entry {
+    bytes: Vec<u8>, // Chunk::encode
+    dirty: bool, // has edits that aren't on disk yet
+    used: u64, // tick when it was last inserted
+}
+
+// recently unloaded chunks, kept compressed so coming back to them is cheap
+#[derive(Debug)]
+pub struct ChunkCache {
+    entries: HashMap<[i32; 3], Entry>,
+    tick: u64,
+    memory: usize,
+}
+
+impl ChunkCache {
+    pub fn new() -> ChunkCache {
+        ChunkCache {
+            entries: HashMap::new(),
+            tick: 0,
+            memory: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // bytes held by the compressed chunks
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    pub fn contains(&self, position: [i32; 3]) -> bool {
+        self.entries.contains_key(&position)
+    }
+
+    pub fn insert(&mut self, chunk: &Chunk) {
+        self.tick += 1;
+
+        let entry = Entry {
+            bytes: chunk.encode(),
+            dirty: chunk.dirty.save,
+            used: self.tick,
+        };
+
+        self.memory += entry_size(&entry);
+        if let Some(old) = self.entries.insert(chunk.position(), entry) {
+            self.memory -= entry_size(&old);
+        }
+    }
+
+    pub fn take(&mut self, position: [i32; 3]) -> Option<Chunk> {
+        let entry = match self.entries.remove(&position) {
+            Some(entry) => entry,
+            None => return None,
+        };
+        self.memory -= entry_size(&entry);
+
+        Chunk::decode(position, &entry.bytes).map(|mut chunk| {
+            chunk.dirty.save = entry.dirty;
+            chunk
+        })
+    }
+
+    // decoded copies of every entry with unsaved edits
+    pub fn dirty(&self) -> Vec<Chunk> {
+        self.entries.iter()
+            .filter(|&(_, entry)| entry.dirty)
+            .filter_map(|(position, entry)| Chunk::decode(*position, &entry.bytes))
+            .collect()
+    }
+
+    pub fn mark_clean(&mut self) {
+        for entry in self.entries.values_mut() {
+            entry.dirty = false;
+        }
+    }
+
+    // drops the least recently used entries until at most `memory` bytes are held,
+    // entries with unsaved edits are handed back so they can be written out
+    pub fn evict(&mut self, memory: usize) -> Vec<Chunk> {
+        let mut evicted = Vec::new();
+
+        if self.memory <= memory {
+            return evicted;
+        }
+
+        let mut order = self.entries.iter()
+            .map(|(position, entry)| (entry.used, *position))
+            .collect::<Vec<_>>();
+        order.sort();
+
+        for (_, position) in order {
+            if self.memory <= memory {
+                break;
+            }
+
+            if let Some(entry) = self.entries.remove(&position) {
+                self.memory -= entry_size(&entry);
+
+                if entry.dirty {
+                    if let Some(mut chunk) = Chunk::decode(position, &entry.bytes) {
+                        chunk.dirty.save = true;
+                        evicted.push(chunk);
+                    }
+                }
+            }
+        }
+
+        evicted
+    }
+}
+
+fn entry_size(entry: &Entry) -> usize {
+    mem::size_of::<Entry>() + mem::size_of::<[i32; 3]>() + entry.bytes.capacity()
+}
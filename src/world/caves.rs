@@ -0,0 +1,216 @@
+use std::f32::consts::PI;
+
+use rand::Rng;
+
+use super::chunk::{Chunk, SIZE};
+use super::noise::{self, Noise};
+use super::pipeline::{Region, Stage, Status};
+
+// carves caves out of generated chunks with 3d noise and random walk worms
+//
+// every chunk replays the worms that start near it and only carves its own voxels,
+// so the result doesn't depend on the order chunks are generated in
+#[derive(Clone, Debug)]
+pub struct CaveStage {
+    pub density: f32, // 0..1, how much of the band the noise caves take up
+    pub scale: f32, // voxels across one noise cell
+    pub worms: f32, // average worms starting in each chunk
+    pub length: u32, // steps a worm takes, one voxel each
+    pub radius: (f32, f32), // min and max worm radius in voxels
+    pub depth: (i32, i32), // world heights caves are carved between
+}
+
+impl CaveStage {
+    pub fn new() -> CaveStage {
+        CaveStage {
+            density: 0.1,
+            scale: 24.0,
+            worms: 0.5,
+            length: 48,
+            radius: (1.5, 3.5),
+            depth: (-96, -4),
+        }
+    }
+
+    fn in_band(&self, y: i32) -> bool {
+        y >= self.depth.0 && y <= self.depth.1
+    }
+
+    // how many chunks away a worm can start and still reach this one
+    fn spread(&self) -> i32 {
+        let travel = self.length as f32 + self.radius.1;
+        (travel / SIZE as f32).ceil() as i32
+    }
+
+    fn carve_noise(&self, noise: &Noise, chunk: &mut Chunk, origin: [i32; 3]) {
+        if self.density <= 0.0 {
+            return;
+        }
+
+        // thin shells where the noise crosses zero make winding tunnels
+        let threshold = self.density * 0.25;
+
+        for y in 0..SIZE {
+            let world_y = origin[1] + y as i32;
+            if !self.in_band(world_y) {
+                continue;
+            }
+
+            for x in 0..SIZE {
+                for z in 0..SIZE {
+                    let n = noise.fractal3((origin[0] + x as i32) as f32 / self.scale,
+                                           world_y as f32 / self.scale,
+                                           (origin[2] + z as i32) as f32 / self.scale, 2, 0.5);
+
+                    if n.abs() < threshold {
+                        chunk.set([x, y, z], 0);
+                    }
+                }
+            }
+        }
+    }
+
+    fn carve_worms(&self, seed: u64, chunk: &mut Chunk, center: [i32; 3]) {
+        let size = SIZE as i32;
+        let reach = self.spread();
+        let origin = [center[0] * size, center[1] * size, center[2] * size];
+
+        for ox in -reach..reach + 1 {
+            for oy in -reach..reach + 1 {
+                for oz in -reach..reach + 1 {
+                    let start = [center[0] + ox, center[1] + oy, center[2] + oz];
+                    let mut rng = noise::rng(noise::hash(seed, start));
+
+                    // whole worms plus a chance of one more
+                    let mut count = self.worms.floor() as u32;
+                    if rng.gen::<f32>() < self.worms.fract() {
+                        count += 1;
+                    }
+
+                    for _ in 0..count {
+                        let mut position = [
+                            (start[0] * size) as f32 + rng.gen_range(0.0, SIZE as f32),
+                            (start[1] * size) as f32 + rng.gen_range(0.0, SIZE as f32),
+                            (start[2] * size) as f32 + rng.gen_range(0.0, SIZE as f32),
+                        ];
+                        let mut yaw = rng.gen_range(0.0, 2.0 * PI);
+                        let mut pitch = rng.gen_range(-0.5f32, 0.5);
+                        let radius = rng.gen_range(self.radius.0, self.radius.1);
+
+                        for _ in 0..self.length {
+                            if self.in_band(position[1].floor() as i32) {
+                                carve_sphere(chunk, origin, position, radius);
+                            }
+
+                            position[0] += yaw.cos() * pitch.cos();
+                            position[1] += pitch.sin();
+                            position[2] += yaw.sin() * pitch.cos();
+
+                            yaw += rng.gen_range(-0.3, 0.3);
+                            pitch = (pitch + rng.gen_range(-0.2, 0.2)) * 0.9;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// clears the part of a sphere that falls inside the chunk starting at `origin`
+fn carve_sphere(chunk: &mut Chunk, origin: [i32; 3], center: [f32; 3], radius: f32) {
+    let mut min = [0usize; 3];
+    let mut max = [0usize; 3];
+
+    for i in 0..3 {
+        let low = (center[i] - radius).floor() as i32 - origin[i];
+        let high = (center[i] + radius).ceil() as i32 - origin[i];
+
+        if high < 0 || low >= SIZE as i32 {
+            return;
+        }
+
+        min[i] = if low < 0 { 0 } else { low as usize };
+        max[i] = if high >= SIZE as i32 { SIZE - 1 } else { high as usize };
+    }
+
+    for x in min[0]..max[0] + 1 {
+        for y in min[1]..max[1] + 1 {
+            for z in min[2]..max[2] + 1 {
+                let dx = (origin[0] + x as i32) as f32 + 0.5 - center[0];
+                let dy = (origin[1] + y as i32) as f32 + 0.5 - center[1];
+                let dz = (origin[2] + z as i32) as f32 + 0.5 - center[2];
+
+                if dx * dx + dy * dy + dz * dz <= radius * radius {
+                    chunk.set([x, y, z], 0);
+                }
+            }
+        }
+    }
+}
+
+impl Stage for CaveStage {
+    fn status(&self) -> Status {
+        Status::Caves
+    }
+
+    fn reach(&self) -> bool {
+        false
+    }
+
+    fn run(&self, seed: u64, region: &mut Region) {
+        let center = region.center();
+        let origin = region.origin();
+
+        // skip chunks no cave could reach
+        let size = SIZE as i32;
+        let margin = self.length as i32 + self.radius.1.ceil() as i32;
+        if origin[1] + size + margin < self.depth.0 || origin[1] - margin > self.depth.1 {
+            return;
+        }
+
+        let noise = Noise::new(seed ^ 0xca7e);
+        let chunk = region.chunk_mut();
+
+        self.carve_noise(&noise, chunk, origin);
+        self.carve_worms(seed ^ 0x3044, chunk, center);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CaveStage;
+    use world::generator::{ChunkGenerator, TerrainGenerator};
+    use world::pipeline::Pipeline;
+
+    // well under the surface, so everything starts as stone
+    const DEEP: [i32; 3] = [0, -3, 0];
+
+    fn pipeline(seed: u64) -> Pipeline {
+        let mut pipeline = Pipeline::new(seed, TerrainGenerator::new(seed));
+        pipeline.add_stage(CaveStage { worms: 4.0, .. CaveStage::new() });
+        pipeline
+    }
+
+    #[test]
+    fn carves_something() {
+        let chunk = pipeline(5).generate(DEEP);
+        assert!(chunk.encode() != TerrainGenerator::new(5).generate(DEEP).encode());
+    }
+
+    #[test]
+    fn same_seed_same_caves() {
+        assert_eq!(pipeline(5).generate(DEEP).encode(), pipeline(5).generate(DEEP).encode());
+    }
+
+    #[test]
+    fn neighbours_first() {
+        let alone = pipeline(5).generate(DEEP);
+
+        let after = pipeline(5);
+        for position in [[1, -3, 0], [0, -3, -1], [0, -2, 0], [-1, -4, 1]].iter() {
+            after.generate(*position);
+        }
+
+        assert_eq!(after.generate(DEEP).encode(), alone.encode());
+    }
+}
@@ -12,6 +12,7 @@ use collision::{Ray3, Aabb3, Intersect};
 use bit_set::BitSet;
 
 use super::super::Vertex;
+use super::Definition;
 
 pub static VERTICES: [Vertex; 24] = [
     // top (0, 0, 1)
@@ -81,26 +82,183 @@ pub static INDICES: &'static [u16] = &[
     0, 4, 7,
 ];*/
 
+// outward normal of each face, in the same order as the faces in VERTICES
+pub static FACES: [[i32; 3]; 6] = [
+    [0, 0, 1], // top
+    [0, 0, -1], // bottom
+    [1, 0, 0], // right
+    [-1, 0, 0], // left
+    [0, 1, 0], // front
+    [0, -1, 0], // back
+];
+
 gfx_vertex_struct!( InstancedVoxel {
     position: [i32; 4] = "vox_Pos",
     color: [f32; 4] = "vox_Color",
+    faces: u32 = "vox_Faces", // bit per face in FACES order, unset faces are collapsed, bit 8 + face flips its triangles
+    light: [u32; 2] = "vox_Light", // packed light of each face, a byte per face in FACES order
+    ao: [u32; 2] = "vox_Ao", // two bits of ambient occlusion per vertex in VERTICES order
 });
 
+// chunks sharing a face with a chunk, in FACES order
+pub type Neighbours<'a> = [Option<&'a Chunk>; 6];
+
+// every chunk touching a chunk, by face, edge or corner, indexed with `around_index`
+pub type Around<'a> = [Option<&'a Chunk>; 27];
+
+// index into `Around` of the chunk at `offset`, each part -1, 0 or 1
+pub fn around_index(offset: [i32; 3]) -> usize {
+    ((offset[0] + 1) * 9 + (offset[1] + 1) * 3 + (offset[2] + 1)) as usize
+}
+
+// the chunks out of `around` that share a face, in FACES order
+pub fn sides<'a>(around: &Around<'a>) -> Neighbours<'a> {
+    let mut neighbours = [None; 6];
+    for (face, normal) in FACES.iter().enumerate() {
+        neighbours[face] = around[around_index(*normal)];
+    }
+    neighbours
+}
+
+// empty and transparent voxels let the faces behind them be seen
+pub fn is_opaque(definitions: &[Definition], id: u16) -> bool {
+    if id == 0 {
+        return false;
+    }
+
+    match definitions.get(id as usize - 1) {
+        Some(definition) => !definition.is_transparent(),
+        None => true,
+    }
+}
+
+// lets some light through, drawn in the blended pass
+pub fn is_transparent(definitions: &[Definition], id: u16) -> bool {
+    id != 0 && !is_opaque(definitions, id)
+}
+
+// how open a face corner is, from whether the two voxels beside it and the one
+// diagonal to it are solid, 0 is fully occluded and 3 is open
+pub fn ambient_occlusion(side1: bool, side2: bool, corner: bool) -> u8 {
+    if side1 && side2 {
+        0
+    } else {
+        3 - side1 as u8 - side2 as u8 - corner as u8
+    }
+}
+
+// whether a quad with this occlusion per corner, in winding order, should be split along
+// the other diagonal so the darkening doesn't stretch across it
+pub fn flip_quad(ao: [u8; 4]) -> bool {
+    ao[0] as u32 + ao[2] as u32 < ao[1] as u32 + ao[3] as u32
+}
+
+// light level a voxel gives off
+pub fn emission(definitions: &[Definition], id: u16) -> u8 {
+    if id == 0 {
+        return 0;
+    }
+
+    match definitions.get(id as usize - 1) {
+        Some(definition) => definition.emission(),
+        None => 0,
+    }
+}
+
+pub fn color(definitions: &[Definition], id: u16) -> [f32; 4] {
+    if id != 0 {
+        if let Some(definition) = definitions.get(id as usize - 1) {
+            return definition.color();
+        }
+    }
+
+    // colors from before definitions were loaded
+    match id {
+        1 => [0.02, 0.55, 0.0, 1.0],
+        2 => [0.2, 0.2, 0.2, 1.0],
+        3 => [0.4, 0.4, 0.4, 1.0],
+        _ => [0.00, 0.00, 0.00, 0.00],
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Voxel {
     id: u16, // index to a definition
 }
 
+impl Voxel {
+    pub fn new(id: u16) -> Voxel {
+        Voxel { id: id }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+}
+
 impl fmt::Debug for Voxel {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.id)
     }
 }
 
+pub const SIZE: usize = 16;
+pub const SCALE: f32 = 0.5; // size of a voxel in render units, matches voxel.glslv
+
+// the box a chunk's voxels fill in render units
+pub fn bounds(position: [i32; 3]) -> Aabb3<f32> {
+    let size = SIZE as f32;
+    let min = Point3::new(position[0] as f32 * size - 0.5,
+                          position[1] as f32 * size - 0.5,
+                          position[2] as f32 * size - 0.5);
+    let max = Point3::new(min.x + size, min.y + size, min.z + size);
+    Aabb3::new(min * SCALE, max * SCALE)
+}
+
+pub type Data = [[[Voxel; SIZE]; SIZE]; SIZE]; // indexed [y][x][z]
+
+// skylight in the high four bits and block light in the low four, indexed like Data
+pub type Light = [[[u8; SIZE]; SIZE]; SIZE];
+
+// packed light of the open sky, given to faces looking into chunks that aren't loaded
+pub const FULL_SKY: u8 = 15 << 4;
+
+// what needs to be redone for a chunk since it last changed
+#[derive(Copy, Clone, Debug)]
+pub struct Dirty {
+    pub mesh: bool, // instances need rebuilding
+    pub save: bool, // differs from what is on disk
+    pub lighting: bool, // light levels need propagating
+}
+
+impl Dirty {
+    pub fn clean() -> Dirty {
+        Dirty {
+            mesh: false,
+            save: false,
+            lighting: false,
+        }
+    }
+
+    pub fn all() -> Dirty {
+        Dirty {
+            mesh: true,
+            save: true,
+            lighting: true,
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        self.mesh || self.save || self.lighting
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Chunk {
     position: [i32; 3],
-    pub data: [[[Voxel; 16]; 16]; 16], // 16x16x16 array of voxels
+    pub data: Data, // 16x16x16 array of voxels
+    pub light: Light, // filled in by World::update_lighting
+    pub dirty: Dirty,
 }
 
 impl Chunk {
@@ -108,12 +266,76 @@ impl Chunk {
         Chunk {
             position: position,
             data: [[[Voxel { id: 0 }; 16]; 16]; 16],
+            light: [[[0; SIZE]; SIZE]; SIZE],
+            // freshly made chunks still need meshing and lighting, but match the disk
+            dirty: Dirty {
+                mesh: true,
+                save: false,
+                lighting: true,
+            },
         }
     }
 
+    pub fn position(&self) -> [i32; 3] {
+        self.position
+    }
+
+    // local position is [x, y, z]
+    pub fn get(&self, local: [usize; 3]) -> u16 {
+        self.data[local[1]][local[0]][local[2]].id
+    }
+
+    // returns the id that was replaced, the world relights the whole chunk after a write
+    // unless it went through `World::set_voxel`, which updates the light around it instead
+    pub fn set(&mut self, local: [usize; 3], id: u16) -> u16 {
+        let old = self.data[local[1]][local[0]][local[2]].id;
+        if old != id {
+            self.data[local[1]][local[0]][local[2]] = Voxel { id: id };
+            self.dirty.mesh = true;
+            self.dirty.save = true;
+            self.dirty.lighting = true;
+        }
+        old
+    }
+
+    pub fn sky_light(&self, local: [usize; 3]) -> u8 {
+        self.light[local[1]][local[0]][local[2]] >> 4
+    }
+
+    pub fn block_light(&self, local: [usize; 3]) -> u8 {
+        self.light[local[1]][local[0]][local[2]] & 15
+    }
+
+    pub fn set_sky_light(&mut self, local: [usize; 3], level: u8) {
+        let light = &mut self.light[local[1]][local[0]][local[2]];
+        *light = (*light & 15) | (level << 4);
+    }
+
+    pub fn set_block_light(&mut self, local: [usize; 3], level: u8) {
+        let light = &mut self.light[local[1]][local[0]][local[2]];
+        *light = (*light & !15) | (level & 15);
+    }
+
+    // replaces all of the voxels at once
+    pub fn replace(&mut self, data: Data) {
+        self.data = data;
+        self.dirty = Dirty::all();
+    }
+
+    // whether a local position lies on the edge of the chunk
+    pub fn on_border(local: [usize; 3]) -> bool {
+        local.iter().any(|&l| l == 0 || l == SIZE - 1)
+    }
+
     pub fn from(file: &Path, position: [i32; 3], location: u64) -> Option<Chunk> {
-        let mut file = File::open(file).unwrap();
-        file.seek(SeekFrom::Start(location));
+        // a missing or unreadable file fails the chunk instead of the thread parsing it
+        let mut file = match File::open(file) {
+            Ok(file) => file,
+            Err(_) => return None,
+        };
+        if file.seek(SeekFrom::Start(location)).is_err() {
+            return None;
+        }
 
         let mut expr = "".to_owned();
         let (mut x, mut y, mut z) = (0, 0, 0);
@@ -191,6 +413,7 @@ impl Chunk {
                 },
                 Err(e) => {
                     println!("ERR: {:?}", e);
+                    return None;
                 }
             }
         }
@@ -243,44 +466,217 @@ impl Chunk {
         format!("{}{}^\r\n", header, content)
     }
 
-    pub fn stress(range: u32) -> Vec<Chunk> {
-        let mut list = Vec::new();
-
-        for x in 0..range {
-            for z in 0..range {
-                for y in 0..range {
-                    list.push(
-                        Chunk {
-                            position: [x as i32, y as i32, z as i32],
-                            data: [[[Voxel { id: 2 }; 16]; 16]; 16],
-                        }
-                    );
+    // binary form, runs of (id, count) as little endian u16 pairs
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+
+        let mut run: Option<(u16, u16)> = None;
+        for y in self.data.iter() {
+            for x in y.iter() {
+                for z in x.iter() {
+                    run = match run {
+                        Some((id, count)) if id == z.id => Some((id, count + 1)),
+                        Some((id, count)) => {
+                            push_run(&mut bytes, id, count);
+                            Some((z.id, 1))
+                        },
+                        None => Some((z.id, 1)),
+                    };
                 }
             }
         }
 
-        list
+        if let Some((id, count)) = run {
+            push_run(&mut bytes, id, count);
+        }
+
+        bytes
+    }
+
+    pub fn decode(position: [i32; 3], bytes: &[u8]) -> Option<Chunk> {
+        let mut chunk = Chunk::new(position);
+        let mut index = 0;
+
+        for pair in bytes.chunks(4) {
+            if pair.len() < 4 {
+                return None;
+            }
+
+            let id = pair[0] as u16 | (pair[1] as u16) << 8;
+            let count = pair[2] as usize | (pair[3] as usize) << 8;
+
+            if index + count > SIZE * SIZE * SIZE {
+                return None;
+            }
+
+            for i in index..index + count {
+                chunk.data[i / (SIZE * SIZE)][(i / SIZE) % SIZE][i % SIZE] = Voxel { id: id };
+            }
+            index += count;
+        }
+
+        Some(chunk)
+    }
+
+    // the chunk and local position one step from `local` along `normal`, looking
+    // into the neighbouring chunk across a border, None if that isn't loaded
+    fn step<'a>(&'a self, neighbours: &Neighbours<'a>, local: [usize; 3], normal: [i32; 3]) -> Option<(&'a Chunk, [usize; 3])> {
+        let mut position = [0usize; 3];
+        let mut face = None;
+
+        for i in 0..3 {
+            let p = local[i] as i32 + normal[i];
+            if p < 0 || p >= SIZE as i32 {
+                face = FACES.iter().position(|f| *f == normal);
+            }
+            position[i] = ((p + SIZE as i32) % SIZE as i32) as usize;
+        }
+
+        match face {
+            None => Some((self, position)),
+            Some(face) => neighbours[face].map(|chunk| (chunk, position)),
+        }
+    }
+
+    // id of the voxel one step from `local` along `normal`, chunks that aren't loaded read as empty
+    pub fn neighbour(&self, neighbours: &Neighbours, local: [usize; 3], normal: [i32; 3]) -> u16 {
+        self.step(neighbours, local, normal).map(|(chunk, position)| chunk.get(position)).unwrap_or(0)
+    }
+
+    // packed light reaching the face of `local` along `normal`, from the voxel it looks into
+    pub fn face_light(&self, neighbours: &Neighbours, local: [usize; 3], normal: [i32; 3]) -> u8 {
+        match self.step(neighbours, local, normal) {
+            Some((chunk, position)) => chunk.light[position[1]][position[0]][position[2]],
+            None => FULL_SKY,
+        }
+    }
+
+    // whether the voxel at `offset` from `local` is opaque, looking into whichever chunk
+    // around this one it falls in, chunks that aren't loaded read as empty
+    fn occludes(&self, around: &Around, definitions: &[Definition], local: [usize; 3], offset: [i32; 3]) -> bool {
+        let mut position = [0usize; 3];
+        let mut chunk = [0i32; 3];
+
+        for i in 0..3 {
+            let p = local[i] as i32 + offset[i];
+            if p < 0 || p >= SIZE as i32 {
+                chunk[i] = if p < 0 { -1 } else { 1 };
+            }
+            position[i] = ((p + SIZE as i32) % SIZE as i32) as usize;
+        }
+
+        let id = if chunk == [0, 0, 0] {
+            self.get(position)
+        } else {
+            around[around_index(chunk)].map(|chunk| chunk.get(position)).unwrap_or(0)
+        };
+
+        is_opaque(definitions, id)
+    }
+
+    // ambient occlusion of the corners of one face, in VERTICES order
+    pub fn face_ao(&self, around: &Around, definitions: &[Definition], local: [usize; 3], face: usize) -> [u8; 4] {
+        let normal = FACES[face];
+        let mut ao = [3; 4];
+
+        for corner in 0..4 {
+            let pos = VERTICES[face * 4 + corner].pos;
+            let (mut side1, mut side2, mut diagonal) = (normal, normal, normal);
+
+            // the two axes along the face, stepping towards this corner
+            let mut tangents = (0..3).filter(|i| normal[*i] == 0);
+            if let (Some(u), Some(v)) = (tangents.next(), tangents.next()) {
+                let (du, dv) = (if pos[u] < 0.0 { -1 } else { 1 }, if pos[v] < 0.0 { -1 } else { 1 });
+                side1[u] += du;
+                side2[v] += dv;
+                diagonal[u] += du;
+                diagonal[v] += dv;
+            }
+
+            ao[corner] = ambient_occlusion(self.occludes(around, definitions, local, side1),
+                                           self.occludes(around, definitions, local, side2),
+                                           self.occludes(around, definitions, local, diagonal));
+        }
+
+        ao
+    }
+
+    // face bits of a voxel that aren't hidden behind an opaque neighbour, or
+    // between two of the same transparent voxel like the inside of a pool of water
+    pub fn visible_faces(&self, neighbours: &Neighbours, definitions: &[Definition], local: [usize; 3]) -> u32 {
+        let id = self.get(local);
+        let transparent = is_transparent(definitions, id);
+
+        let mut faces = 0;
+        for (face, normal) in FACES.iter().enumerate() {
+            let next = self.neighbour(neighbours, local, *normal);
+            if !is_opaque(definitions, next) && !(transparent && next == id) {
+                faces |= 1 << face;
+            }
+        }
+        faces
+    }
+
+    // one instance per voxel with at least one visible face
+    // opaque voxels go in `list` and transparent ones in `transparent`
+    pub fn instances(&self, around: &Around, definitions: &[Definition],
+                     list: &mut Vec<InstancedVoxel>, transparent: &mut Vec<InstancedVoxel>) {
+        self.push_instances(around, definitions, Some(list), transparent);
     }
 
-    pub fn instances(&self, list: &mut Vec<InstancedVoxel>) {
+    // only the transparent voxels, for when the opaque ones are meshed
+    pub fn transparent_instances(&self, around: &Around, definitions: &[Definition], transparent: &mut Vec<InstancedVoxel>) {
+        self.push_instances(around, definitions, None, transparent);
+    }
+
+    fn push_instances(&self, around: &Around, definitions: &[Definition],
+                      mut list: Option<&mut Vec<InstancedVoxel>>, transparent: &mut Vec<InstancedVoxel>) {
+        let neighbours = sides(around);
+
         for (y_pos, y) in self.data.iter().enumerate() {
             for (x_pos, x) in y.iter().enumerate() {
                 for (z_pos, z) in x.iter().enumerate() {
+                    let clear = is_transparent(definitions, z.id);
 
-                    if z.id != 0 {
-                        let mut color = match z.id {
-                            1 => [0.02, 0.55, 0.0, 1.0],
-                            2 => [0.2, 0.2, 0.2, 1.0],
-                            3 => [0.4, 0.4, 0.4, 1.0],
-                            _ => [0.00, 0.00, 0.00, 0.00],
-                        };
-                        list.push(InstancedVoxel {
+                    if z.id != 0 && (clear || list.is_some()) {
+                        let local = [x_pos, y_pos, z_pos];
+                        let mut faces = self.visible_faces(&neighbours, definitions, local);
+                        if faces == 0 {
+                            continue;
+                        }
+
+                        let mut light = [0u32; 2];
+                        let mut ao = [0u32; 2];
+                        for (face, normal) in FACES.iter().enumerate() {
+                            light[face / 4] |= (self.face_light(&neighbours, local, *normal) as u32) << (face % 4 * 8);
+
+                            let corners = self.face_ao(around, definitions, local, face);
+                            for (corner, value) in corners.iter().enumerate() {
+                                let vertex = face * 4 + corner;
+                                ao[vertex / 16] |= (*value as u32) << (vertex % 16 * 2);
+                            }
+
+                            if flip_quad(corners) {
+                                faces |= 1 << (8 + face);
+                            }
+                        }
+
+                        let instance = InstancedVoxel {
                             position: [
                                 self.position[0] * 16 + x_pos as i32,
                                 self.position[1] * 16 + y_pos as i32,
                                 self.position[2] * 16 + z_pos as i32, 1],
-                            color: color,
-                        });
+                            color: color(definitions, z.id),
+                            faces: faces,
+                            light: light,
+                            ao: ao,
+                        };
+
+                        if clear {
+                            transparent.push(instance);
+                        } else if let Some(ref mut list) = list {
+                            list.push(instance);
+                        }
                     }
                 }
             }
@@ -302,11 +698,18 @@ impl Chunk {
         let ray_aabb = (ray, aabb);
 
         if let Some(_) = ray_aabb.intersection() {
-            self.data[y][x][z] = Voxel { id: 3 };
+            self.set([x, y, z], 3);
         } else {
-            self.data[y][x][z] = Voxel { id: 1 };
+            self.set([x, y, z], 1);
         }
 
         true
     }
+}
+
+fn push_run(bytes: &mut Vec<u8>, id: u16, count: u16) {
+    bytes.push(id as u8);
+    bytes.push((id >> 8) as u8);
+    bytes.push(count as u8);
+    bytes.push((count >> 8) as u8);
 }
\ No newline at end of file
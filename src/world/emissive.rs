@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+
+use super::{Definition, split};
+use super::chunk::{self, Chunk, SIZE};
+
+// a voxel that gives off light
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Emitter {
+    pub position: [i32; 3], // world voxel position
+    pub id: u16,
+    pub level: u8,
+}
+
+// every emitter in the loaded chunks, listed per chunk
+#[derive(Debug)]
+pub struct Emitters {
+    chunks: HashMap<[i32; 3], Vec<Emitter>>,
+}
+
+impl Emitters {
+    pub fn new() -> Emitters {
+        Emitters {
+            chunks: HashMap::new(),
+        }
+    }
+
+    // replaces what we know about the chunk's emitters
+    pub fn scan(&mut self, chunk: &Chunk, definitions: &[Definition]) {
+        let position = chunk.position();
+        self.chunks.remove(&position);
+
+        // most worlds have nothing that glows, no need to look
+        if !definitions.iter().any(|definition| definition.is_emissive()) {
+            return;
+        }
+
+        let size = SIZE as i32;
+        let mut emitters = Vec::new();
+        for x in 0..SIZE {
+            for y in 0..SIZE {
+                for z in 0..SIZE {
+                    let id = chunk.get([x, y, z]);
+                    let level = chunk::emission(definitions, id);
+                    if level > 0 {
+                        emitters.push(Emitter {
+                            position: [position[0] * size + x as i32, position[1] * size + y as i32, position[2] * size + z as i32],
+                            id: id,
+                            level: level,
+                        });
+                    }
+                }
+            }
+        }
+
+        if !emitters.is_empty() {
+            self.chunks.insert(position, emitters);
+        }
+    }
+
+    pub fn remove(&mut self, position: [i32; 3]) {
+        self.chunks.remove(&position);
+    }
+
+    // call after the voxel at a world position changed to `id`
+    pub fn set(&mut self, position: [i32; 3], id: u16, definitions: &[Definition]) {
+        let (chunk, _) = split(position);
+        let level = chunk::emission(definitions, id);
+
+        let empty = {
+            let emitters = self.chunks.entry(chunk).or_insert_with(Vec::new);
+            emitters.retain(|emitter| emitter.position != position);
+            if level > 0 {
+                emitters.push(Emitter {
+                    position: position,
+                    id: id,
+                    level: level,
+                });
+            }
+            emitters.is_empty()
+        };
+
+        if empty {
+            self.chunks.remove(&chunk);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.values().fold(0, |sum, emitters| sum + emitters.len())
+    }
+
+    // the `count` emitters closest to a point in world voxel units
+    pub fn near(&self, point: [f32; 3], count: usize) -> Vec<Emitter> {
+        let distance = |emitter: &Emitter| {
+            let (x, y, z) = (emitter.position[0] as f32 - point[0],
+                             emitter.position[1] as f32 - point[1],
+                             emitter.position[2] as f32 - point[2]);
+            x * x + y * y + z * z
+        };
+
+        let mut emitters = self.chunks.values()
+            .flat_map(|emitters| emitters.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        emitters.sort_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap());
+        emitters.truncate(count);
+        emitters
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WorldEvent {
+    ChunkLoaded([i32; 3]), // chunk position
+    ChunkUnloaded([i32; 3]),
+    VoxelChanged {
+        position: [i32; 3], // world voxel position
+        old: u16,
+        new: u16,
+    },
+    RegionChanged {
+        min: [i32; 3], // inclusive world voxel bounds
+        max: [i32; 3],
+    },
+}
+
+pub type ObserverId = usize;
+
+// a queue of events per observer, each observer drains its own queue
+#[derive(Debug)]
+pub struct Events {
+    queues: Vec<Option<VecDeque<WorldEvent>>>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events {
+            queues: Vec::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self) -> ObserverId {
+        // reuse the slot of an observer that went away
+        if let Some(id) = self.queues.iter().position(|queue| queue.is_none()) {
+            self.queues[id] = Some(VecDeque::new());
+            return id;
+        }
+
+        self.queues.push(Some(VecDeque::new()));
+        self.queues.len() - 1
+    }
+
+    pub fn unsubscribe(&mut self, id: ObserverId) {
+        if id < self.queues.len() {
+            self.queues[id] = None;
+        }
+    }
+
+    pub fn publish(&mut self, event: WorldEvent) {
+        for queue in self.queues.iter_mut() {
+            if let Some(ref mut queue) = *queue {
+                queue.push_back(event);
+            }
+        }
+    }
+
+    pub fn poll(&mut self, id: ObserverId) -> Option<WorldEvent> {
+        match self.queues.get_mut(id) {
+            Some(&mut Some(ref mut queue)) => queue.pop_front(),
+            _ => None,
+        }
+    }
+
+    pub fn drain(&mut self, id: ObserverId) -> Vec<WorldEvent> {
+        match self.queues.get_mut(id) {
+            Some(&mut Some(ref mut queue)) => queue.drain(..).collect(),
+            _ => Vec::new(),
+        }
+    }
+}
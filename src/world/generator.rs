@@ -0,0 +1,123 @@
+use std::fmt;
+
+use super::chunk::{Chunk, SIZE};
+use super::noise::Noise;
+
+// makes chunks that aren't in the wrld file, must give the same chunk for the same position
+pub trait ChunkGenerator: fmt::Debug + Send + Sync {
+    fn generate(&self, position: [i32; 3]) -> Chunk;
+}
+
+// rolling hills of layered noise, surface over subsurface over stone
+#[derive(Clone, Debug)]
+pub struct TerrainGenerator {
+    seed: u64,
+    noise: Noise,
+
+    pub height: f32, // average surface height in voxels
+    pub amplitude: f32, // how far the surface strays from `height`
+    pub scale: f32, // voxels across one noise cell
+    pub octaves: u32,
+    pub persistence: f32,
+
+    pub surface: u16, // voxel ids
+    pub subsurface: u16,
+    pub stone: u16,
+    pub depth: i32, // subsurface voxels between the surface and stone
+}
+
+impl TerrainGenerator {
+    pub fn new(seed: u64) -> TerrainGenerator {
+        TerrainGenerator {
+            seed: seed,
+            noise: Noise::new(seed),
+
+            height: 0.0,
+            amplitude: 12.0,
+            scale: 64.0,
+            octaves: 4,
+            persistence: 0.5,
+
+            surface: 1,
+            subsurface: 2,
+            stone: 3,
+            depth: 3,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // surface height in voxels of a world column
+    pub fn height_at(&self, x: i32, z: i32) -> i32 {
+        let n = self.noise.fractal2(x as f32 / self.scale, z as f32 / self.scale, self.octaves, self.persistence);
+        (self.height + n * self.amplitude).floor() as i32
+    }
+}
+
+impl ChunkGenerator for TerrainGenerator {
+    fn generate(&self, position: [i32; 3]) -> Chunk {
+        let mut chunk = Chunk::new(position);
+        let size = SIZE as i32;
+
+        for x in 0..SIZE {
+            for z in 0..SIZE {
+                let height = self.height_at(position[0] * size + x as i32, position[2] * size + z as i32);
+
+                for y in 0..SIZE {
+                    let world_y = position[1] * size + y as i32;
+
+                    let id = if world_y > height {
+                        0
+                    } else if world_y == height {
+                        self.surface
+                    } else if world_y > height - self.depth {
+                        self.subsurface
+                    } else {
+                        self.stone
+                    };
+
+                    if id != 0 {
+                        chunk.set([x, y, z], id);
+                    }
+                }
+            }
+        }
+
+        // generated chunks can be remade from the seed, so only edits need saving
+        chunk.dirty.save = false;
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChunkGenerator, TerrainGenerator};
+
+    // chunks around the surface, where the hills are
+    const POSITIONS: [[i32; 3]; 4] = [[0, 0, 0], [0, -1, 0], [3, 0, -2], [-5, -1, 7]];
+
+    #[test]
+    fn same_seed_same_chunks() {
+        let (first, second) = (TerrainGenerator::new(7), TerrainGenerator::new(7));
+        for position in POSITIONS.iter() {
+            assert_eq!(first.generate(*position).encode(), second.generate(*position).encode());
+        }
+    }
+
+    #[test]
+    fn same_chunk_twice() {
+        let generator = TerrainGenerator::new(7);
+        let before = generator.generate([1, 0, 1]).encode();
+        generator.generate([2, 0, 1]);
+        assert_eq!(generator.generate([1, 0, 1]).encode(), before);
+    }
+
+    #[test]
+    fn different_seeds_differ() {
+        let (first, second) = (TerrainGenerator::new(7), TerrainGenerator::new(8));
+        let differ = POSITIONS.iter().any(|position| first.generate(*position).encode() != second.generate(*position).encode());
+        assert!(differ);
+    }
+}
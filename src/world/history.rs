@@ -0,0 +1,425 @@
+use std::mem;
+use std::collections::{HashMap, VecDeque};
+
+use super::chunk::Data;
+
+// a single voxel edit in world coordinates
+#[derive(Copy, Clone, Debug)]
+pub struct Change {
+    pub position: [i32; 3],
+    pub old: u16,
+    pub new: u16,
+}
+
+#[derive(Debug)]
+pub enum Edit {
+    Voxel(Change),
+    // whole chunk before and after, used when a transaction touches most of a chunk
+    Snapshot {
+        chunk: [i32; 3],
+        old: Box<Data>,
+        new: Box<Data>,
+    },
+}
+
+impl Edit {
+    pub fn memory(&self) -> usize {
+        match *self {
+            Edit::Voxel(_) => mem::size_of::<Edit>(),
+            Edit::Snapshot { .. } => mem::size_of::<Edit>() + 2 * mem::size_of::<Data>(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Transaction {
+    id: u64,
+    pub edits: Vec<Edit>,
+}
+
+impl Transaction {
+    pub fn new(id: u64) -> Transaction {
+        Transaction {
+            id: id,
+            edits: Vec::new(),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn memory(&self) -> usize {
+        self.edits.iter().fold(mem::size_of::<Transaction>(), |sum, edit| sum + edit.memory())
+    }
+
+    // replaces the voxel edits of any chunk with more than `threshold` changes
+    // by a snapshot of that chunk, `current` gives the chunk data after the edits
+    pub fn compact<F>(&mut self, threshold: usize, current: F)
+        where F: Fn([i32; 3]) -> Option<Data>
+    {
+        let mut counts = HashMap::new();
+        for edit in self.edits.iter() {
+            if let Edit::Voxel(ref change) = *edit {
+                let (chunk, _) = super::split(change.position);
+                *counts.entry(chunk).or_insert(0) += 1;
+            }
+        }
+
+        for (chunk, count) in counts {
+            if count <= threshold {
+                continue;
+            }
+
+            let new = match current(chunk) {
+                Some(data) => data,
+                None => continue,
+            };
+
+            // walk backwards so the oldest value of a voxel wins
+            let mut old = new;
+            for edit in self.edits.iter().rev() {
+                if let Edit::Voxel(ref change) = *edit {
+                    let (position, local) = super::split(change.position);
+                    if position == chunk {
+                        old[local[1]][local[0]][local[2]] = super::chunk::Voxel::new(change.old);
+                    }
+                }
+            }
+
+            self.edits.retain(|edit| {
+                match *edit {
+                    Edit::Voxel(ref change) => super::split(change.position).0 != chunk,
+                    _ => true,
+                }
+            });
+
+            self.edits.push(Edit::Snapshot {
+                chunk: chunk,
+                old: Box::new(old),
+                new: Box::new(new),
+            });
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct History {
+    undo: VecDeque<Transaction>,
+    redo: Vec<Transaction>,
+    current: Option<Transaction>,
+
+    next_id: u64,
+    saved: u64, // id of the transaction on top of the undo stack when last saved, 0 if empty
+    saved_lost: bool, // the saved state was trimmed away or overwritten
+
+    pub limit: usize, // memory cap in bytes
+    pub snapshot_threshold: usize, // voxel edits in one chunk before it becomes a snapshot
+    memory: usize,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            current: None,
+
+            next_id: 1,
+            saved: 0,
+            saved_lost: false,
+
+            limit: 16 * 1024 * 1024,
+            snapshot_threshold: 512,
+            memory: 0,
+        }
+    }
+
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    pub fn recording(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    // opens a transaction, everything recorded until `end` is undone as one step
+    pub fn begin(&mut self) {
+        if self.current.is_none() {
+            let id = self.next_id;
+            self.next_id += 1;
+            self.current = Some(Transaction::new(id));
+        }
+    }
+
+    pub fn record(&mut self, change: Change) {
+        if change.old == change.new {
+            return;
+        }
+
+        if let Some(ref mut transaction) = self.current {
+            transaction.edits.push(Edit::Voxel(change));
+            return;
+        }
+
+        // not inside a transaction, so the change is its own step
+        let mut transaction = Transaction::new(self.next_id);
+        self.next_id += 1;
+        transaction.edits.push(Edit::Voxel(change));
+        self.push(transaction);
+    }
+
+    // closes the open transaction, returning it so the caller can compact it
+    pub fn end(&mut self) -> Option<Transaction> {
+        self.current.take()
+    }
+
+    // pushes a finished transaction onto the undo stack, invalidating redo
+    pub fn push(&mut self, transaction: Transaction) {
+        if transaction.edits.is_empty() {
+            return;
+        }
+
+        if self.redo.iter().any(|t| t.id == self.saved) {
+            self.saved_lost = true;
+        }
+        self.redo.clear();
+
+        self.memory += transaction.memory();
+        self.undo.push_back(transaction);
+        self.trim();
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Transaction> {
+        let transaction = self.undo.pop_back();
+        if let Some(ref t) = transaction {
+            self.memory -= t.memory();
+        }
+        transaction
+    }
+
+    pub fn push_redo(&mut self, transaction: Transaction) {
+        self.redo.push(transaction);
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Transaction> {
+        self.redo.pop()
+    }
+
+    // pushes a redone transaction back without clearing the redo stack
+    pub fn push_undo(&mut self, transaction: Transaction) {
+        self.memory += transaction.memory();
+        self.undo.push_back(transaction);
+        self.trim();
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved = self.undo.back().map(|t| t.id).unwrap_or(0);
+        self.saved_lost = false;
+    }
+
+    // whether the world matches what was last written to disk
+    pub fn is_saved(&self) -> bool {
+        !self.saved_lost && self.saved == self.undo.back().map(|t| t.id).unwrap_or(0)
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.current = None;
+        self.memory = 0;
+        self.saved = 0;
+        self.saved_lost = false;
+    }
+
+    // drops the oldest transactions until we're under the memory cap
+    fn trim(&mut self) {
+        while self.memory > self.limit && self.undo.len() > 1 {
+            if let Some(oldest) = self.undo.pop_front() {
+                self.memory -= oldest.memory();
+
+                // an empty undo stack now means "after the oldest transaction"
+                if oldest.id == self.saved {
+                    self.saved = 0;
+                } else if self.saved == 0 {
+                    self.saved_lost = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, Edit, History, Transaction};
+    use world::chunk::{Chunk, Voxel};
+
+    fn change(x: i32, old: u16, new: u16) -> Change {
+        Change {
+            position: [x, 0, 0],
+            old: old,
+            new: new,
+        }
+    }
+
+    fn changes(transaction: &Transaction) -> Vec<(i32, u16, u16)> {
+        transaction.edits.iter().filter_map(|edit| match *edit {
+            Edit::Voxel(ref change) => Some((change.position[0], change.old, change.new)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut history = History::new();
+        history.record(change(0, 0, 1));
+        history.record(change(1, 0, 2));
+        assert!(history.can_undo() && !history.can_redo());
+
+        // the latest step comes back first
+        let last = history.pop_undo().unwrap();
+        assert_eq!(changes(&last), vec![(1, 0, 2)]);
+        history.push_redo(last);
+        assert!(history.can_redo());
+
+        let redone = history.pop_redo().unwrap();
+        assert_eq!(changes(&redone), vec![(1, 0, 2)]);
+        history.push_undo(redone);
+        assert!(!history.can_redo());
+
+        // a new edit after an undo throws the redo away
+        let last = history.pop_undo().unwrap();
+        history.push_redo(last);
+        history.record(change(2, 0, 3));
+        assert!(!history.can_redo());
+        assert_eq!(changes(&history.pop_undo().unwrap()), vec![(2, 0, 3)]);
+        assert_eq!(changes(&history.pop_undo().unwrap()), vec![(0, 0, 1)]);
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn nested_transactions_are_one_step() {
+        let mut history = History::new();
+        history.begin();
+        history.record(change(0, 0, 1));
+        history.begin();
+        history.record(change(1, 0, 1));
+        assert!(history.recording());
+
+        let transaction = history.end().unwrap();
+        assert!(!history.recording());
+        assert_eq!(changes(&transaction), vec![(0, 0, 1), (1, 0, 1)]);
+
+        history.push(transaction);
+        history.pop_undo().unwrap();
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn no_op_changes_are_dropped() {
+        let mut history = History::new();
+        history.record(change(0, 4, 4));
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn busy_chunks_become_snapshots() {
+        let mut transaction = Transaction::new(1);
+
+        // eleven edits in the first chunk, the first voxel twice, and one in the next
+        transaction.edits.push(Edit::Voxel(change(0, 5, 6)));
+        for x in 0..10 {
+            transaction.edits.push(Edit::Voxel(change(x, if x == 0 { 6 } else { 0 }, 7)));
+        }
+        transaction.edits.push(Edit::Voxel(change(16, 0, 7)));
+
+        transaction.compact(10, |position| {
+            let mut data = Chunk::new(position).data;
+            for x in 0..10 {
+                data[0][x][0] = Voxel::new(7);
+            }
+            Some(data)
+        });
+
+        assert_eq!(changes(&transaction), vec![(16, 0, 7)]);
+
+        let snapshots = transaction.edits.iter().filter_map(|edit| match *edit {
+            Edit::Snapshot { chunk, ref old, ref new } => Some((chunk, old.clone(), new.clone())),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(snapshots.len(), 1);
+
+        let (position, old, new) = snapshots[0].clone();
+        let (mut before, mut after) = (Chunk::new(position), Chunk::new(position));
+        before.data = *old;
+        after.data = *new;
+
+        assert_eq!(position, [0, 0, 0]);
+        assert_eq!(before.get([0, 0, 0]), 5); // the oldest value of a voxel edited twice
+        assert_eq!(before.get([3, 0, 0]), 0);
+        assert_eq!(after.get([3, 0, 0]), 7);
+    }
+
+    #[test]
+    fn few_edits_stay_voxels() {
+        let mut transaction = Transaction::new(1);
+        transaction.edits.push(Edit::Voxel(change(0, 0, 1)));
+        transaction.compact(10, |position| Some(Chunk::new(position).data));
+        assert_eq!(changes(&transaction), vec![(0, 0, 1)]);
+    }
+
+    #[test]
+    fn saving_during_a_transaction() {
+        let mut history = History::new();
+        history.begin();
+        history.record(change(0, 0, 1));
+
+        // what's on disk doesn't have the open transaction's edits
+        history.mark_saved();
+        assert!(history.is_saved());
+
+        let transaction = history.end().unwrap();
+        history.push(transaction);
+        assert!(!history.is_saved());
+
+        // undoing gets back to what was saved
+        history.pop_undo();
+        assert!(history.is_saved());
+    }
+
+    #[test]
+    fn trimming_keeps_track_of_the_save() {
+        let mut history = History::new();
+        history.record(change(0, 0, 1));
+        history.mark_saved();
+
+        // the saved step goes, an empty stack then stands for the state right after it
+        history.limit = 0;
+        history.record(change(1, 0, 1));
+        assert!(!history.is_saved());
+        history.pop_undo();
+        assert!(history.is_saved());
+    }
+
+    #[test]
+    fn trimming_past_the_save_loses_it() {
+        let mut history = History::new();
+        history.mark_saved();
+
+        // saved before anything happened, that state can't be undone back to anymore
+        history.limit = 0;
+        history.record(change(0, 0, 1));
+        history.record(change(1, 0, 1));
+        history.pop_undo();
+        assert!(!history.can_undo());
+        assert!(!history.is_saved());
+    }
+}
@@ -0,0 +1,389 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::{Definition, split};
+use super::chunk::{self, Chunk, FACES, SIZE};
+
+pub const MAX_LIGHT: u8 = 15;
+
+const DOWN: [i32; 3] = [0, -1, 0];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Channel {
+    Sky, // from open sky above, falls straight down without dimming
+    Block, // from emissive voxels
+}
+
+// flood fills light through the loaded chunks, a voxel gets one less than its brightest neighbour
+pub struct Lighter<'a> {
+    chunks: &'a mut [Chunk],
+    index: HashMap<[i32; 3], usize>,
+    definitions: &'a [Definition],
+}
+
+impl<'a> Lighter<'a> {
+    pub fn new(chunks: &'a mut [Chunk], definitions: &'a [Definition]) -> Lighter<'a> {
+        let index = chunks.iter()
+            .enumerate()
+            .map(|(i, chunk)| (chunk.position(), i))
+            .collect();
+
+        Lighter {
+            chunks: chunks,
+            index: index,
+            definitions: definitions,
+        }
+    }
+
+    fn locate(&self, position: [i32; 3]) -> Option<(usize, [usize; 3])> {
+        let (chunk, local) = split(position);
+        self.index.get(&chunk).map(|index| (*index, local))
+    }
+
+    // None if the chunk isn't loaded
+    pub fn id(&self, position: [i32; 3]) -> Option<u16> {
+        self.locate(position).map(|(index, local)| self.chunks[index].get(local))
+    }
+
+    pub fn get(&self, position: [i32; 3], channel: Channel) -> Option<u8> {
+        self.locate(position).map(|(index, local)| match channel {
+            Channel::Sky => self.chunks[index].sky_light(local),
+            Channel::Block => self.chunks[index].block_light(local),
+        })
+    }
+
+    fn set(&mut self, position: [i32; 3], channel: Channel, level: u8) {
+        let (index, local) = match self.locate(position) {
+            Some(found) => found,
+            None => return,
+        };
+
+        {
+            let chunk = &mut self.chunks[index];
+            match channel {
+                Channel::Sky => chunk.set_sky_light(local, level),
+                Channel::Block => chunk.set_block_light(local, level),
+            }
+            chunk.dirty.mesh = true;
+        }
+
+        // faces of the neighbouring chunk can look into this voxel
+        if Chunk::on_border(local) {
+            let (chunk, _) = split(position);
+            for i in 0..3 {
+                let mut neighbour = chunk;
+                if local[i] == 0 {
+                    neighbour[i] -= 1;
+                } else if local[i] == SIZE - 1 {
+                    neighbour[i] += 1;
+                } else {
+                    continue;
+                }
+
+                if let Some(index) = self.index.get(&neighbour) {
+                    self.chunks[*index].dirty.mesh = true;
+                }
+            }
+        }
+    }
+
+    fn transmits(&self, position: [i32; 3]) -> bool {
+        match self.id(position) {
+            Some(id) => !chunk::is_opaque(self.definitions, id),
+            None => false,
+        }
+    }
+
+    fn emission(&self, position: [i32; 3]) -> u8 {
+        self.id(position).map_or(0, |id| chunk::emission(self.definitions, id))
+    }
+
+    // spreads light outwards from every queued voxel until it runs out
+    pub fn propagate(&mut self, mut queue: VecDeque<[i32; 3]>, channel: Channel) {
+        while let Some(position) = queue.pop_front() {
+            let level = self.get(position, channel).unwrap_or(0);
+            if level == 0 {
+                continue;
+            }
+
+            for normal in FACES.iter() {
+                let next = [position[0] + normal[0], position[1] + normal[1], position[2] + normal[2]];
+                if !self.transmits(next) {
+                    continue;
+                }
+
+                let spread = if channel == Channel::Sky && *normal == DOWN && level == MAX_LIGHT {
+                    MAX_LIGHT
+                } else {
+                    level - 1
+                };
+
+                if self.get(next, channel).unwrap_or(MAX_LIGHT) < spread {
+                    self.set(next, channel, spread);
+                    queue.push_back(next);
+                }
+            }
+        }
+    }
+
+    // darkens everything lit by the queued voxels, given with the level they had,
+    // then fills back in from whatever light is left around them
+    pub fn remove(&mut self, mut queue: VecDeque<([i32; 3], u8)>, channel: Channel) {
+        let mut relight = VecDeque::new();
+
+        while let Some((position, level)) = queue.pop_front() {
+            for normal in FACES.iter() {
+                let next = [position[0] + normal[0], position[1] + normal[1], position[2] + normal[2]];
+                let next_level = match self.get(next, channel) {
+                    Some(next_level) if next_level > 0 => next_level,
+                    _ => continue,
+                };
+
+                let falling = channel == Channel::Sky && *normal == DOWN && level == MAX_LIGHT;
+                if next_level < level || falling {
+                    self.set(next, channel, 0);
+                    queue.push_back((next, next_level));
+
+                    // sources keep shining
+                    if channel == Channel::Block && self.emission(next) > 0 {
+                        let emission = self.emission(next);
+                        self.set(next, channel, emission);
+                        relight.push_back(next);
+                    }
+                } else {
+                    relight.push_back(next);
+                }
+            }
+        }
+
+        self.propagate(relight, channel);
+    }
+
+    // lights a chunk from scratch, from open sky, its emitters and its neighbours
+    pub fn relight_chunk(&mut self, position: [i32; 3]) {
+        let index = match self.index.get(&position) {
+            Some(index) => *index,
+            None => return,
+        };
+
+        let size = SIZE as i32;
+        let origin = [position[0] * size, position[1] * size, position[2] * size];
+        let world = |local: [usize; 3]| [origin[0] + local[0] as i32, origin[1] + local[1] as i32, origin[2] + local[2] as i32];
+
+        for &channel in [Channel::Sky, Channel::Block].iter() {
+            // take out whatever light the chunk had, along with what it spread
+            let mut removed = VecDeque::new();
+            for x in 0..SIZE {
+                for y in 0..SIZE {
+                    for z in 0..SIZE {
+                        let level = match channel {
+                            Channel::Sky => self.chunks[index].sky_light([x, y, z]),
+                            Channel::Block => self.chunks[index].block_light([x, y, z]),
+                        };
+                        if level > 0 {
+                            self.set(world([x, y, z]), channel, 0);
+                            removed.push_back((world([x, y, z]), level));
+                        }
+                    }
+                }
+            }
+            self.remove(removed, channel);
+
+            let mut queue = VecDeque::new();
+
+            // light already around the chunk flows in
+            for x in -1..size + 1 {
+                for y in -1..size + 1 {
+                    for z in -1..size + 1 {
+                        let outside = [x, y, z].iter().filter(|c| **c < 0 || **c >= size).count();
+                        if outside == 1 {
+                            let next = [origin[0] + x, origin[1] + y, origin[2] + z];
+                            if self.get(next, channel).unwrap_or(0) > 0 {
+                                queue.push_back(next);
+                            }
+                        }
+                    }
+                }
+            }
+
+            match channel {
+                Channel::Sky => {
+                    // with nothing loaded above, the sky is assumed open
+                    let above = [position[0], position[1] + 1, position[2]];
+                    if !self.index.contains_key(&above) {
+                        for x in 0..SIZE {
+                            for z in 0..SIZE {
+                                let top = world([x, SIZE - 1, z]);
+                                if self.transmits(top) {
+                                    self.set(top, channel, MAX_LIGHT);
+                                    queue.push_back(top);
+                                }
+                            }
+                        }
+                    }
+                },
+                Channel::Block => {
+                    for x in 0..SIZE {
+                        for y in 0..SIZE {
+                            for z in 0..SIZE {
+                                let emission = chunk::emission(self.definitions, self.chunks[index].get([x, y, z]));
+                                if emission > 0 {
+                                    self.set(world([x, y, z]), channel, emission);
+                                    queue.push_back(world([x, y, z]));
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+
+            self.propagate(queue, channel);
+        }
+
+        // the chunk below assumed open sky while this one wasn't loaded, take back what we now block
+        let mut blocked = VecDeque::new();
+        for x in 0..SIZE {
+            for z in 0..SIZE {
+                let bottom = world([x, 0, z]);
+                let below = [bottom[0], bottom[1] - 1, bottom[2]];
+                if self.get(below, Channel::Sky) == Some(MAX_LIGHT) && self.get(bottom, Channel::Sky) != Some(MAX_LIGHT) {
+                    self.set(below, Channel::Sky, 0);
+                    blocked.push_back((below, MAX_LIGHT));
+                }
+            }
+        }
+        self.remove(blocked, Channel::Sky);
+    }
+
+    // updates the light around a voxel that was just changed
+    pub fn voxel_changed(&mut self, position: [i32; 3]) {
+        for &channel in [Channel::Sky, Channel::Block].iter() {
+            let level = match self.get(position, channel) {
+                Some(level) => level,
+                None => continue,
+            };
+
+            if level > 0 {
+                self.set(position, channel, 0);
+                let mut removed = VecDeque::new();
+                removed.push_back((position, level));
+                self.remove(removed, channel);
+            }
+
+            let mut queue = VecDeque::new();
+            if channel == Channel::Block && self.emission(position) > 0 {
+                let emission = self.emission(position);
+                self.set(position, channel, emission);
+                queue.push_back(position);
+            }
+
+            // an opening lets the light around it back in
+            if self.transmits(position) {
+                for normal in FACES.iter() {
+                    queue.push_back([position[0] + normal[0], position[1] + normal[1], position[2] + normal[2]]);
+                }
+
+                // open sky straight above with nothing loaded there
+                let above = [position[0], position[1] + 1, position[2]];
+                if channel == Channel::Sky && self.get(above, channel).is_none() {
+                    self.set(position, channel, MAX_LIGHT);
+                    queue.push_back(position);
+                }
+            }
+
+            self.propagate(queue, channel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, Lighter, MAX_LIGHT};
+    use world::Definition;
+    use world::chunk::{Chunk, SIZE};
+
+    const STONE: u16 = 1;
+    const LAMP: u16 = 2;
+
+    fn definitions() -> Vec<Definition> {
+        let mut lamp = Definition::new("lamp");
+        lamp.attribute("e", "14");
+        vec![Definition::new("stone"), lamp]
+    }
+
+    // a single lit chunk at the origin, so world and local positions are the same
+    fn lit(voxels: &[([usize; 3], u16)]) -> Vec<Chunk> {
+        let mut chunk = Chunk::new([0, 0, 0]);
+        for &(local, id) in voxels.iter() {
+            chunk.set(local, id);
+        }
+
+        let definitions = definitions();
+        let mut chunks = vec![chunk];
+        Lighter::new(&mut chunks, &definitions).relight_chunk([0, 0, 0]);
+        chunks
+    }
+
+    #[test]
+    fn sky_falls_down_open_columns() {
+        let mut chunks = lit(&[([8, 12, 8], STONE)]);
+        let definitions = definitions();
+        let lighter = Lighter::new(&mut chunks, &definitions);
+
+        // open columns are lit all the way down
+        for y in 0..SIZE as i32 {
+            assert_eq!(lighter.get([2, y, 2], Channel::Sky), Some(MAX_LIGHT));
+        }
+
+        // under the stone only light from the side gets in
+        assert_eq!(lighter.get([8, 11, 8], Channel::Sky), Some(MAX_LIGHT - 1));
+        assert_eq!(lighter.get([8, 0, 8], Channel::Sky), Some(MAX_LIGHT - 1));
+    }
+
+    #[test]
+    fn block_light_falls_off_from_emitters() {
+        let mut chunks = lit(&[([8, 8, 8], LAMP)]);
+        let definitions = definitions();
+        let lighter = Lighter::new(&mut chunks, &definitions);
+
+        assert_eq!(lighter.get([8, 8, 8], Channel::Block), Some(14));
+        for distance in 1..6 {
+            assert_eq!(lighter.get([8 + distance, 8, 8], Channel::Block), Some(14 - distance as u8));
+            assert_eq!(lighter.get([8, 8 - distance, 8], Channel::Block), Some(14 - distance as u8));
+        }
+
+        // steps along more than one axis add up
+        assert_eq!(lighter.get([10, 10, 8], Channel::Block), Some(10));
+    }
+
+    #[test]
+    fn placing_and_removing_voxels() {
+        let mut chunks = lit(&[([8, 8, 8], LAMP)]);
+        let definitions = definitions();
+
+        // a stone in an open column shades everything under it
+        chunks[0].set([2, 10, 2], STONE);
+        Lighter::new(&mut chunks, &definitions).voxel_changed([2, 10, 2]);
+        {
+            let lighter = Lighter::new(&mut chunks, &definitions);
+            assert_eq!(lighter.get([2, 10, 2], Channel::Sky), Some(0));
+            assert_eq!(lighter.get([2, 9, 2], Channel::Sky), Some(MAX_LIGHT - 1));
+        }
+
+        // and taking it away lets the sky back in
+        chunks[0].set([2, 10, 2], 0);
+        Lighter::new(&mut chunks, &definitions).voxel_changed([2, 10, 2]);
+        {
+            let lighter = Lighter::new(&mut chunks, &definitions);
+            assert_eq!(lighter.get([2, 10, 2], Channel::Sky), Some(MAX_LIGHT));
+            assert_eq!(lighter.get([2, 0, 2], Channel::Sky), Some(MAX_LIGHT));
+        }
+
+        // removing the lamp takes its light with it
+        chunks[0].set([8, 8, 8], 0);
+        Lighter::new(&mut chunks, &definitions).voxel_changed([8, 8, 8]);
+        let lighter = Lighter::new(&mut chunks, &definitions);
+        for distance in 0..6 {
+            assert_eq!(lighter.get([8 + distance, 8, 8], Channel::Block), Some(0));
+        }
+    }
+}
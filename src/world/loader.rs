@@ -0,0 +1,233 @@
+use std::thread;
+use std::path::PathBuf;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+use super::chunk::Chunk;
+use super::generator::ChunkGenerator;
+
+// where a worker should read a chunk from
+#[derive(Clone, Debug)]
+pub enum Source {
+    Text {
+        file: PathBuf, // wrld file
+        location: u64, // offset of the chunk body
+    },
+    Binary(Vec<u8>), // from Chunk::encode
+    Generate(Arc<ChunkGenerator>), // not on disk
+}
+
+#[derive(Debug)]
+struct Request {
+    position: [i32; 3],
+    source: Source,
+    priority: i32, // squared chunk distance from the center, lower loads first
+}
+
+#[derive(Debug)]
+struct Queue {
+    pending: Vec<Request>,
+    wanted: HashSet<[i32; 3]>, // pending or being parsed, and not cancelled
+    center: [i32; 3],
+    shutdown: bool,
+}
+
+impl Queue {
+    fn take_closest(&mut self) -> Option<Request> {
+        let closest = self.pending.iter()
+            .enumerate()
+            .min_by_key(|&(_, request)| request.priority)
+            .map(|(index, _)| index);
+
+        closest.map(|index| self.pending.swap_remove(index))
+    }
+}
+
+fn distance(a: [i32; 3], b: [i32; 3]) -> i32 {
+    let (x, y, z) = (a[0] - b[0], a[1] - b[1], a[2] - b[2]);
+    x * x + y * y + z * z
+}
+
+// parses chunks on worker threads, finished chunks are collected with `finished`
+pub struct ChunkLoader {
+    shared: Arc<(Mutex<Queue>, Condvar)>,
+    results: Receiver<([i32; 3], Option<Chunk>)>,
+    workers: Vec<thread::JoinHandle<()>>,
+    capacity: usize, // most requests waiting at once
+}
+
+impl ChunkLoader {
+    pub fn new(threads: usize, capacity: usize) -> ChunkLoader {
+        let shared = Arc::new((Mutex::new(Queue {
+            pending: Vec::new(),
+            wanted: HashSet::new(),
+            center: [0, 0, 0],
+            shutdown: false,
+        }), Condvar::new()));
+
+        let (sender, receiver) = channel();
+
+        let mut workers = Vec::new();
+        for _ in 0..threads {
+            let shared = shared.clone();
+            let sender = sender.clone();
+            workers.push(thread::spawn(move || work(shared, sender)));
+        }
+
+        ChunkLoader {
+            shared: shared,
+            results: receiver,
+            workers: workers,
+            capacity: capacity,
+        }
+    }
+
+    // queues a chunk, false if it is already queued or the queue is full of closer chunks
+    pub fn request(&self, position: [i32; 3], source: Source) -> bool {
+        let &(ref lock, ref condvar) = &*self.shared;
+        let mut queue = lock.lock().unwrap();
+
+        if queue.wanted.contains(&position) {
+            return false;
+        }
+
+        let priority = distance(position, queue.center);
+
+        if queue.pending.len() >= self.capacity {
+            // make room by dropping the furthest request if we're closer
+            let furthest = queue.pending.iter()
+                .enumerate()
+                .max_by_key(|&(_, request)| request.priority)
+                .map(|(index, request)| (index, request.priority));
+
+            match furthest {
+                Some((index, furthest)) if furthest > priority => {
+                    let dropped = queue.pending.swap_remove(index);
+                    queue.wanted.remove(&dropped.position);
+                },
+                _ => return false,
+            }
+        }
+
+        queue.wanted.insert(position);
+        queue.pending.push(Request {
+            position: position,
+            source: source,
+            priority: priority,
+        });
+
+        condvar.notify_one();
+        true
+    }
+
+    // drops a request, a chunk already being parsed is thrown away when it finishes
+    pub fn cancel(&self, position: [i32; 3]) {
+        let &(ref lock, _) = &*self.shared;
+        let mut queue = lock.lock().unwrap();
+
+        queue.pending.retain(|request| request.position != position);
+        queue.wanted.remove(&position);
+    }
+
+    // cancels everything that `keep` returns false for
+    pub fn retain<F>(&self, keep: F) where F: Fn([i32; 3]) -> bool {
+        let &(ref lock, _) = &*self.shared;
+        let mut queue = lock.lock().unwrap();
+
+        queue.pending.retain(|request| keep(request.position));
+        let wanted: HashSet<_> = queue.wanted.iter().cloned().filter(|position| keep(*position)).collect();
+        queue.wanted = wanted;
+    }
+
+    // chunks closest to `center` are parsed first
+    pub fn set_center(&self, center: [i32; 3]) {
+        let &(ref lock, _) = &*self.shared;
+        let mut queue = lock.lock().unwrap();
+
+        if queue.center != center {
+            queue.center = center;
+            for request in queue.pending.iter_mut() {
+                request.priority = distance(request.position, center);
+            }
+        }
+    }
+
+    pub fn is_requested(&self, position: [i32; 3]) -> bool {
+        let &(ref lock, _) = &*self.shared;
+        lock.lock().unwrap().wanted.contains(&position)
+    }
+
+    pub fn pending(&self) -> usize {
+        let &(ref lock, _) = &*self.shared;
+        lock.lock().unwrap().wanted.len()
+    }
+
+    // chunks parsed since the last call, without blocking
+    pub fn finished(&self) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+
+        let &(ref lock, _) = &*self.shared;
+        while let Ok((position, chunk)) = self.results.try_recv() {
+            let mut queue = lock.lock().unwrap();
+
+            // cancelled while it was being parsed
+            if !queue.wanted.remove(&position) {
+                continue;
+            }
+
+            match chunk {
+                Some(chunk) => chunks.push(chunk),
+                None => println!("Failed to load chunk {:?}", position),
+            }
+        }
+
+        chunks
+    }
+}
+
+impl Drop for ChunkLoader {
+    fn drop(&mut self) {
+        {
+            let &(ref lock, ref condvar) = &*self.shared;
+            lock.lock().unwrap().shutdown = true;
+            condvar.notify_all();
+        }
+
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+    }
+}
+
+fn work(shared: Arc<(Mutex<Queue>, Condvar)>, sender: Sender<([i32; 3], Option<Chunk>)>) {
+    let &(ref lock, ref condvar) = &*shared;
+
+    loop {
+        let request = {
+            let mut queue = lock.lock().unwrap();
+            while !queue.shutdown && queue.pending.is_empty() {
+                queue = condvar.wait(queue).unwrap();
+            }
+
+            if queue.shutdown {
+                return;
+            }
+
+            match queue.take_closest() {
+                Some(request) => request,
+                None => continue,
+            }
+        };
+
+        let chunk = match request.source {
+            Source::Text { ref file, location } => Chunk::from(file, request.position, location),
+            Source::Binary(ref bytes) => Chunk::decode(request.position, bytes),
+            Source::Generate(ref generator) => Some(generator.generate(request.position)),
+        };
+
+        if sender.send((request.position, chunk)).is_err() {
+            return;
+        }
+    }
+}
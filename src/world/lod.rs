@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use super::Definition;
+use super::chunk::{self, Chunk, FACES, FULL_SKY, SIZE};
+use super::mesh::{Mesh, Quad};
+
+// voxels per cell side at each level, level 0 is the full chunk
+pub const FACTORS: [usize; 4] = [1, 2, 4, 8];
+
+// picks a level for each chunk from its distance to the camera's chunk
+#[derive(Clone, Debug)]
+pub struct LodSettings {
+    pub distances: [i32; 3], // chunk distance at which levels 1, 2 and 3 start
+}
+
+impl LodSettings {
+    pub fn new() -> LodSettings {
+        LodSettings {
+            distances: [2, 4, 6],
+        }
+    }
+
+    pub fn level(&self, center: [i32; 3], position: [i32; 3]) -> usize {
+        let (x, y, z) = (position[0] - center[0], position[1] - center[1], position[2] - center[2]);
+        let distance = x * x + y * y + z * z;
+
+        self.distances.iter()
+            .take_while(|start| distance >= **start * **start)
+            .count()
+    }
+}
+
+// the chunk at a coarser level, each cell holds the most common id of the
+// voxels it covers, indexed [y][x][z] like `Chunk::data`
+#[derive(Clone, Debug)]
+pub struct Downsampled {
+    pub factor: usize,
+    pub cells: Vec<u16>,
+}
+
+impl Downsampled {
+    pub fn new(chunk: &Chunk, factor: usize) -> Downsampled {
+        let size = SIZE / factor;
+        let mut cells = vec![0; size * size * size];
+        let mut counts = HashMap::new();
+
+        for cy in 0..size {
+            for cx in 0..size {
+                for cz in 0..size {
+                    counts.clear();
+                    for y in cy * factor..(cy + 1) * factor {
+                        for x in cx * factor..(cx + 1) * factor {
+                            for z in cz * factor..(cz + 1) * factor {
+                                *counts.entry(chunk.get([x, y, z])).or_insert(0) += 1;
+                            }
+                        }
+                    }
+
+                    // ties go to the solid material so thin surfaces don't vanish
+                    let mut best = (0, 0);
+                    for (&id, &count) in counts.iter() {
+                        let tie = count == best.1 && (best.0 == 0 || (id != 0 && id < best.0));
+                        if count > best.1 || tie {
+                            best = (id, count);
+                        }
+                    }
+
+                    cells[(cy * size + cx) * size + cz] = best.0;
+                }
+            }
+        }
+
+        Downsampled {
+            factor: factor,
+            cells: cells,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        SIZE / self.factor
+    }
+
+    // None outside the chunk
+    pub fn get(&self, cell: [i32; 3]) -> Option<u16> {
+        let size = self.size() as i32;
+        if cell.iter().any(|c| *c < 0 || *c >= size) {
+            return None;
+        }
+
+        let (x, y, z) = (cell[0] as usize, cell[1] as usize, cell[2] as usize);
+        Some(self.cells[(y * self.size() + x) * self.size() + z])
+    }
+}
+
+// meshes of the chunk at a coarser level, the opaque cells and then the transparent
+// ones to blend over them, faces on the chunk border are always kept so neighbours
+// at a different level never show a crack through the seam
+pub fn mesh(chunk: &Chunk, level: usize, definitions: &[Definition]) -> (Mesh, Mesh) {
+    let factor = FACTORS[level];
+    let coarse = Downsampled::new(chunk, factor);
+    let size = coarse.size() as i32;
+    let position = chunk.position();
+    let origin = [position[0] * SIZE as i32, position[1] * SIZE as i32, position[2] * SIZE as i32];
+    let mut opaque = Mesh::new();
+    let mut transparent = Mesh::new();
+
+    for x in 0..size {
+        for y in 0..size {
+            for z in 0..size {
+                let id = coarse.get([x, y, z]).unwrap_or(0);
+                if id == 0 {
+                    continue;
+                }
+
+                // no faces inside a body of the same transparent material either
+                let clear = chunk::is_transparent(definitions, id);
+                let mesh = if clear { &mut transparent } else { &mut opaque };
+
+                for (face, normal) in FACES.iter().enumerate() {
+                    let next = coarse.get([x + normal[0], y + normal[1], z + normal[2]]);
+                    if let Some(next) = next {
+                        if chunk::is_opaque(definitions, next) || (clear && next == id) {
+                            continue;
+                        }
+                    }
+
+                    let mut quad = Quad {
+                        position: [origin[0] + x * factor as i32, origin[1] + y * factor as i32, origin[2] + z * factor as i32],
+                        size: [factor as i32, factor as i32],
+                        face: face,
+                        id: id,
+                    };
+
+                    // quads sit on the far side of the voxel at their position when facing positive
+                    let (axis, _, _) = quad.axes();
+                    if quad.positive() {
+                        quad.position[axis] += factor as i32 - 1;
+                    }
+
+                    // light of the voxel just outside the middle of the face, open sky past the border
+                    let mut outside = [0i32; 3];
+                    let mut light = FULL_SKY;
+                    let cell = [x, y, z];
+                    for i in 0..3 {
+                        outside[i] = cell[i] * factor as i32 + match normal[i] {
+                            1 => factor as i32,
+                            -1 => -1,
+                            _ => factor as i32 / 2,
+                        };
+                    }
+                    if outside.iter().all(|p| *p >= 0 && *p < SIZE as i32) {
+                        light = chunk.light[outside[1] as usize][outside[0] as usize][outside[2] as usize];
+                    }
+
+                    // occlusion from the cells around each corner, cells past the border read as empty
+                    let (_, u, v) = quad.axes();
+                    let occludes = |offset: [i32; 3]| {
+                        let next = [cell[0] + normal[0] + offset[0], cell[1] + normal[1] + offset[1], cell[2] + normal[2] + offset[2]];
+                        coarse.get(next).map_or(false, |id| chunk::is_opaque(definitions, id))
+                    };
+
+                    let mut ao = [3; 4];
+                    for (i, &(du, dv)) in [(-1, -1), (1, -1), (1, 1), (-1, 1)].iter().enumerate() {
+                        let (mut side1, mut side2) = ([0; 3], [0; 3]);
+                        side1[u] = du;
+                        side2[v] = dv;
+                        let mut diagonal = side1;
+                        diagonal[v] = dv;
+
+                        ao[i] = chunk::ambient_occlusion(occludes(side1), occludes(side2), occludes(diagonal));
+                    }
+
+                    let light = [(light >> 4) as f32 / 15.0, (light & 15) as f32 / 15.0];
+                    mesh.push(&quad, chunk::color(definitions, id), light, ao);
+                }
+            }
+        }
+    }
+
+    (opaque, transparent)
+}
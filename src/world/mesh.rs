@@ -0,0 +1,445 @@
+use super::Definition;
+use super::chunk::{self, Around, Chunk, Neighbours, FACES, SIZE};
+
+gfx_vertex_struct!( MeshVertex {
+    pos: [f32; 4] = "vert_Pos", // world voxel units, scaled in the shader like instances
+    normal: [i8; 4] = "vert_Normal",
+    color: [f32; 4] = "vert_Color",
+    light: [f32; 2] = "vert_Light", // sky and block light levels, 0 to 1
+    ao: f32 = "vert_Ao", // ambient occlusion, 0 fully occluded to 1 open
+});
+
+// a rectangle of faces of one material, lying on one side of a layer of voxels
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quad {
+    pub position: [i32; 3], // world position of the voxel at the quad's minimum corner
+    pub size: [i32; 2], // voxels along the face's u and v axes
+    pub face: usize, // index into FACES
+    pub id: u16,
+}
+
+impl Quad {
+    // axis of the normal, then the two axes spanning the face
+    pub fn axes(&self) -> (usize, usize, usize) {
+        axes(self.face)
+    }
+
+    pub fn positive(&self) -> bool {
+        let (axis, _, _) = self.axes();
+        FACES[self.face][axis] > 0
+    }
+
+    // the unit faces the quad covers, as (voxel position, face, id)
+    pub fn cells(&self) -> Vec<([i32; 3], usize, u16)> {
+        let (_, u, v) = self.axes();
+        let mut cells = Vec::new();
+
+        for i in 0..self.size[0] {
+            for j in 0..self.size[1] {
+                let mut position = self.position;
+                position[u] += i;
+                position[v] += j;
+                cells.push((position, self.face, self.id));
+            }
+        }
+
+        cells
+    }
+}
+
+fn axes(face: usize) -> (usize, usize, usize) {
+    let normal = FACES[face];
+    let axis = if normal[0] != 0 { 0 } else if normal[1] != 0 { 1 } else { 2 };
+    // u x v points along +axis
+    (axis, (axis + 1) % 3, (axis + 2) % 3)
+}
+
+// triangles ready for a vertex and index buffer
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    pub vertices: Vec<MeshVertex>,
+    pub indices: Vec<u16>,
+}
+
+impl Mesh {
+    pub fn new() -> Mesh {
+        Mesh {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    pub fn triangles(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    // quads lit by the open sky, what the greedy mesher makes since it merges across light levels
+    pub fn from_quads(quads: &[Quad], definitions: &[Definition]) -> Mesh {
+        let mut mesh = Mesh::new();
+        for quad in quads.iter() {
+            mesh.push(quad, chunk::color(definitions, quad.id), [1.0, 0.0], [3; 4]);
+        }
+        mesh
+    }
+
+    // `ao` is the occlusion at the corners (0, 0), (w, 0), (w, h) and (0, h) along the quad's axes
+    pub fn push(&mut self, quad: &Quad, color: [f32; 4], light: [f32; 2], ao: [u8; 4]) {
+        let (axis, u, v) = quad.axes();
+        let normal = FACES[quad.face];
+
+        // voxels are centered on their position, so faces sit half a voxel out
+        let mut base = [quad.position[0] as f32 - 0.5, quad.position[1] as f32 - 0.5, quad.position[2] as f32 - 0.5];
+        if quad.positive() {
+            base[axis] += 1.0;
+        }
+
+        let corner = |du: i32, dv: i32, ao: u8| {
+            let mut pos = base;
+            pos[u] += du as f32;
+            pos[v] += dv as f32;
+            MeshVertex {
+                pos: [pos[0], pos[1], pos[2], 1.0],
+                normal: [normal[0] as i8, normal[1] as i8, normal[2] as i8, 1],
+                color: color,
+                light: light,
+                ao: ao as f32 / 3.0,
+            }
+        };
+
+        let (w, h) = (quad.size[0], quad.size[1]);
+        let corners = [corner(0, 0, ao[0]), corner(w, 0, ao[1]), corner(w, h, ao[2]), corner(0, h, ao[3])];
+
+        // counter clockwise seen from outside
+        let order = if quad.positive() { [0, 1, 2, 3] } else { [0, 3, 2, 1] };
+
+        let start = self.vertices.len() as u16;
+        for &i in order.iter() {
+            self.vertices.push(corners[i]);
+        }
+
+        let wound = [ao[order[0]], ao[order[1]], ao[order[2]], ao[order[3]]];
+        if chunk::flip_quad(wound) {
+            self.indices.extend_from_slice(&[start + 1, start + 2, start + 3, start + 3, start, start + 1]);
+        } else {
+            self.indices.extend_from_slice(&[start, start + 1, start + 2, start + 2, start + 3, start]);
+        }
+    }
+
+    // reorders the quads farthest from `eye` first so blending builds up in the right
+    // order, `eye` in voxel units like the vertices
+    pub fn sort_back_to_front(&mut self, eye: [f32; 3]) {
+        let vertices = &self.vertices;
+        let key = |quad: &[u16]| {
+            let (mut min, mut max) = ([::std::f32::MAX; 3], [::std::f32::MIN; 3]);
+            for &i in quad.iter() {
+                let pos = vertices[i as usize].pos;
+                for axis in 0..3 {
+                    min[axis] = min[axis].min(pos[axis]);
+                    max[axis] = max[axis].max(pos[axis]);
+                }
+            }
+
+            let mut distance = 0.0;
+            for axis in 0..3 {
+                let d = (min[axis] + max[axis]) * 0.5 - eye[axis];
+                distance += d * d;
+            }
+            distance
+        };
+
+        let mut quads = self.indices.chunks(6).map(|quad| (key(quad), quad.to_vec())).collect::<Vec<_>>();
+        quads.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+        self.indices = quads.into_iter().flat_map(|(_, quad)| quad).collect();
+    }
+}
+
+fn origin(chunk: &Chunk) -> [i32; 3] {
+    let position = chunk.position();
+    let size = SIZE as i32;
+    [position[0] * size, position[1] * size, position[2] * size]
+}
+
+// a quad for every visible face, what instancing draws
+pub fn naive(chunk: &Chunk, neighbours: &Neighbours, definitions: &[Definition]) -> Vec<Quad> {
+    let origin = origin(chunk);
+    let mut quads = Vec::new();
+
+    for x in 0..SIZE {
+        for y in 0..SIZE {
+            for z in 0..SIZE {
+                let id = chunk.get([x, y, z]);
+                if id == 0 {
+                    continue;
+                }
+
+                let faces = chunk.visible_faces(neighbours, definitions, [x, y, z]);
+                for face in 0..FACES.len() {
+                    if faces & (1 << face) != 0 {
+                        quads.push(Quad {
+                            position: [origin[0] + x as i32, origin[1] + y as i32, origin[2] + z as i32],
+                            size: [1, 1],
+                            face: face,
+                            id: id,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    quads
+}
+
+// what neighbouring faces have to share to be merged into one quad
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Cell {
+    id: u16,
+    light: u8, // packed like `Chunk::light`
+    ao: [u8; 4], // at the corners along the face's axes, in `Mesh::push` order
+}
+
+impl Cell {
+    // occlusion that varies across a face only looks right on a face of its own
+    fn uniform(&self) -> bool {
+        self.ao.iter().all(|ao| *ao == self.ao[0])
+    }
+}
+
+// occlusion of a face from `Chunk::face_ao`, reordered to the corners `Mesh::push` takes
+fn corner_ao(face: usize, ao: [u8; 4]) -> [u8; 4] {
+    let (_, u, v) = axes(face);
+    let mut corners = [3; 4];
+
+    for (corner, value) in ao.iter().enumerate() {
+        let pos = chunk::VERTICES[face * 4 + corner].pos;
+        let index = match (pos[u] > 0.0, pos[v] > 0.0) {
+            (false, false) => 0,
+            (true, false) => 1,
+            (true, true) => 2,
+            (false, true) => 3,
+        };
+        corners[index] = *value;
+    }
+
+    corners
+}
+
+// merges neighbouring faces with the same cell into as few rectangles as it can, given
+// `around` faces also need the same light and occlusion
+fn merge(chunk: &Chunk, neighbours: &Neighbours, definitions: &[Definition], around: Option<&Around>) -> Vec<(Quad, Cell)> {
+    let origin = origin(chunk);
+    let mut quads = Vec::new();
+    let mut mask = [[None; SIZE]; SIZE]; // [u][v], the visible face's cell
+
+    // visible face bits of every voxel, indexed like `Chunk::data`
+    let mut visible = vec![0u32; SIZE * SIZE * SIZE];
+    for x in 0..SIZE {
+        for y in 0..SIZE {
+            for z in 0..SIZE {
+                if chunk.get([x, y, z]) != 0 {
+                    visible[(y * SIZE + x) * SIZE + z] = chunk.visible_faces(neighbours, definitions, [x, y, z]);
+                }
+            }
+        }
+    }
+
+    for face in 0..FACES.len() {
+        let (axis, u, v) = axes(face);
+
+        for layer in 0..SIZE {
+            for i in 0..SIZE {
+                for j in 0..SIZE {
+                    let mut local = [0; 3];
+                    local[axis] = layer;
+                    local[u] = i;
+                    local[v] = j;
+
+                    let faces = visible[(local[1] * SIZE + local[0]) * SIZE + local[2]];
+                    mask[i][j] = if faces & (1 << face) == 0 {
+                        None
+                    } else if let Some(around) = around {
+                        Some(Cell {
+                            id: chunk.get(local),
+                            light: chunk.face_light(neighbours, local, FACES[face]),
+                            ao: corner_ao(face, chunk.face_ao(around, definitions, local, face)),
+                        })
+                    } else {
+                        Some(Cell {
+                            id: chunk.get(local),
+                            light: 0,
+                            ao: [3; 4],
+                        })
+                    };
+                }
+            }
+
+            for i in 0..SIZE {
+                let mut j = 0;
+                while j < SIZE {
+                    let cell = match mask[i][j] {
+                        Some(cell) => cell,
+                        None => {
+                            j += 1;
+                            continue;
+                        },
+                    };
+
+                    // grow along v, then along u while the whole row matches
+                    let mut h = 1;
+                    while cell.uniform() && j + h < SIZE && mask[i][j + h] == Some(cell) {
+                        h += 1;
+                    }
+
+                    let mut w = 1;
+                    'grow: while cell.uniform() && i + w < SIZE {
+                        for k in j..j + h {
+                            if mask[i + w][k] != Some(cell) {
+                                break 'grow;
+                            }
+                        }
+                        w += 1;
+                    }
+
+                    for a in i..i + w {
+                        for b in j..j + h {
+                            mask[a][b] = None;
+                        }
+                    }
+
+                    let mut position = origin;
+                    position[axis] += layer as i32;
+                    position[u] += i as i32;
+                    position[v] += j as i32;
+
+                    quads.push((Quad {
+                        position: position,
+                        size: [w as i32, h as i32],
+                        face: face,
+                        id: cell.id,
+                    }, cell));
+
+                    j += h;
+                }
+            }
+        }
+    }
+
+    quads
+}
+
+// merges neighbouring visible faces of the same material into as few rectangles as it can,
+// covers exactly the same faces as `naive`
+pub fn greedy(chunk: &Chunk, neighbours: &Neighbours, definitions: &[Definition]) -> Vec<Quad> {
+    merge(chunk, neighbours, definitions, None).into_iter().map(|(quad, _)| quad).collect()
+}
+
+// the opaque faces of a chunk merged where light and occlusion match, what level 0 draws,
+// transparent voxels are left to the blended pass
+pub fn lit(chunk: &Chunk, around: &Around, definitions: &[Definition]) -> Mesh {
+    let mut mesh = Mesh::new();
+
+    for (quad, cell) in merge(chunk, &chunk::sides(around), definitions, Some(around)) {
+        if chunk::is_transparent(definitions, quad.id) {
+            continue;
+        }
+
+        let light = [(cell.light >> 4) as f32 / 15.0, (cell.light & 15) as f32 / 15.0];
+        mesh.push(&quad, chunk::color(definitions, quad.id), light, cell.ao);
+    }
+
+    mesh
+}
+
+// every unit face the quads cover, sorted, for comparing meshers
+pub fn coverage(quads: &[Quad]) -> Vec<([i32; 3], usize, u16)> {
+    let mut cells = quads.iter().flat_map(|quad| quad.cells().into_iter()).collect::<Vec<_>>();
+    cells.sort();
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coverage, greedy, naive};
+    use world::Definition;
+    use world::chunk::{Chunk, Neighbours, FACES, SIZE};
+
+    // stone, dirt and glass
+    fn definitions() -> Vec<Definition> {
+        let mut glass = Definition::new("glass");
+        glass.attribute("c", "190, 225, 255, 90");
+        vec![Definition::new("stone"), Definition::new("dirt"), glass]
+    }
+
+    fn filled<F>(position: [i32; 3], id: F) -> Chunk where F: Fn(usize, usize, usize) -> u16 {
+        let mut chunk = Chunk::new(position);
+        for x in 0..SIZE {
+            for y in 0..SIZE {
+                for z in 0..SIZE {
+                    chunk.set([x, y, z], id(x, y, z));
+                }
+            }
+        }
+        chunk
+    }
+
+    // greedy covers exactly the faces naive does, returns (greedy, naive) quad counts
+    fn compare(chunk: &Chunk, neighbours: &Neighbours) -> (usize, usize) {
+        let definitions = definitions();
+        let naive = naive(chunk, neighbours, &definitions);
+        let greedy = greedy(chunk, neighbours, &definitions);
+
+        assert_eq!(coverage(&greedy), coverage(&naive));
+        (greedy.len(), naive.len())
+    }
+
+    #[test]
+    fn solid() {
+        let chunk = filled([0, 0, 0], |_, _, _| 1);
+        assert_eq!(compare(&chunk, &[None; 6]), (6, 6 * SIZE * SIZE));
+    }
+
+    #[test]
+    fn checkerboard() {
+        let chunk = filled([0, 0, 0], |x, y, z| if (x + y + z) % 2 == 0 { 1 } else { 0 });
+        let (greedy, naive) = compare(&chunk, &[None; 6]);
+        assert_eq!(greedy, naive);
+    }
+
+    #[test]
+    fn mixed_materials() {
+        // stone and dirt in stripes under a layer of glass
+        let chunk = filled([0, 0, 0], |x, y, z| if y < 8 {
+            if (x / 4 + z / 4) % 2 == 0 { 1 } else { 2 }
+        } else if y < 10 {
+            3
+        } else {
+            0
+        });
+        let (greedy, naive) = compare(&chunk, &[None; 6]);
+        assert!(greedy < naive);
+    }
+
+    #[test]
+    fn culled_by_neighbours() {
+        let chunk = filled([0, 0, 0], |_, _, _| 1);
+        let around = FACES.iter()
+            .map(|normal| filled(*normal, |_, _, _| 1))
+            .collect::<Vec<_>>();
+
+        // buried on every side, nothing is visible
+        let mut neighbours: Neighbours = [None; 6];
+        for (face, neighbour) in around.iter().enumerate() {
+            neighbours[face] = Some(neighbour);
+        }
+        assert_eq!(compare(&chunk, &neighbours), (0, 0));
+
+        // open on top only
+        neighbours[0] = None;
+        assert_eq!(compare(&chunk, &neighbours), (1, SIZE * SIZE));
+    }
+}
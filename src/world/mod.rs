@@ -1,19 +1,149 @@
 
 pub mod chunk;
+pub mod history;
+pub mod event;
+pub mod loader;
+pub mod stream;
+pub mod cache;
+pub mod noise;
+pub mod generator;
+pub mod pipeline;
+pub mod prefab;
+pub mod caves;
+pub mod biome;
+pub mod mesh;
+pub mod lod;
+pub mod visibility;
+pub mod emissive;
+pub mod lighting;
 
+use std::mem;
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
 
 use regex::Regex;
 
-use self::chunk::Chunk;
+use self::chunk::{Around, Chunk, Neighbours, FACES, SIZE};
+use self::history::{History, Change, Edit, Transaction};
+use self::event::{Events, WorldEvent, ObserverId};
+use self::loader::{ChunkLoader, Source};
+use self::cache::ChunkCache;
+use self::generator::ChunkGenerator;
+use self::biome::{Biome, BiomeMap};
+use self::emissive::Emitters;
+use self::lighting::Lighter;
+
+// splits a world voxel position into its chunk position and the local position inside it
+pub fn split(position: [i32; 3]) -> ([i32; 3], [usize; 3]) {
+    let size = SIZE as i32;
+    let mut chunk = [0; 3];
+    let mut local = [0; 3];
+
+    for i in 0..3 {
+        let mut c = position[i] / size;
+        let mut l = position[i] % size;
+        if l < 0 {
+            c -= 1;
+            l += size;
+        }
+
+        chunk[i] = c;
+        local[i] = l as usize;
+    }
+
+    (chunk, local)
+}
 
 #[derive(Debug)]
 pub struct Definition {
     name: String, // identifier
     color: [u8; 4], // color of voxel
+    emission: u8, // light level given off, 0 to 15
+    emission_color: Option<[u8; 3]>, // color of that light if it isn't the voxel's
+}
+
+impl Definition {
+    pub fn new(name: &str) -> Definition {
+        Definition {
+            name: name.to_owned(),
+            color: [255, 255, 255, 255],
+            emission: 0,
+            emission_color: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_transparent(&self) -> bool {
+        self.color[3] < 255
+    }
+
+    pub fn color(&self) -> [f32; 4] {
+        [self.color[0] as f32 / 255.0,
+         self.color[1] as f32 / 255.0,
+         self.color[2] as f32 / 255.0,
+         self.color[3] as f32 / 255.0]
+    }
+
+    pub fn emission(&self) -> u8 {
+        self.emission
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emission > 0
+    }
+
+    pub fn emission_color(&self) -> [f32; 3] {
+        match self.emission_color {
+            Some(color) => [color[0] as f32 / 255.0, color[1] as f32 / 255.0, color[2] as f32 / 255.0],
+            None => {
+                let color = self.color();
+                [color[0], color[1], color[2]]
+            },
+        }
+    }
+
+    // applies one attribute like c(29, 145, 0) or e(15, 255, 120, 0)
+    fn attribute(&mut self, attr_type: &str, attr: &str) {
+        let values = attr.split(',')
+            .filter_map(|value| value.trim().parse::<f32>().ok())
+            .collect::<Vec<_>>();
+
+        match attr_type {
+            "c" => {
+                for (i, value) in values.iter().take(4).enumerate() {
+                    self.color[i] = *value as u8;
+                }
+            },
+            "e" => {
+                if let Some(level) = values.get(0) {
+                    self.emission = level.max(0.0).min(15.0) as u8;
+                }
+                if values.len() >= 4 {
+                    self.emission_color = Some([values[1] as u8, values[2] as u8, values[3] as u8]);
+                }
+            },
+            _ => println!("Unknown attribute {:?} on {:?}", attr_type, self.name),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryUsage {
+    pub loaded: usize, // bytes of chunks in World::chunks
+    pub cached: usize, // bytes of compressed chunks in World::cache
+    pub budget: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.loaded + self.cached
+    }
 }
 
 #[derive(Debug)]
@@ -24,6 +154,15 @@ pub struct World {
     pub definitions: Vec<Definition>,
     pub map: HashMap<[i32; 3], u64>, // location in file
     pub chunks: Vec<Chunk>, // current chunks loaded
+    pub history: History,
+    pub events: Events,
+    pub cache: ChunkCache,
+    pub budget: usize, // bytes for loaded and cached chunks before the cache is evicted
+    pub generator: Option<Arc<ChunkGenerator>>, // fills in chunks missing from the wrld file
+    pub biomes: Option<Arc<BiomeMap>>,
+    pub emitters: Emitters, // emissive voxels of the loaded chunks
+    light_changes: Vec<[i32; 3]>, // voxels changed since light was last updated
+    rewritten: bool, // the wrld file moved around since queued requests read their offsets
 }
 
 impl World {
@@ -35,23 +174,42 @@ impl World {
             definitions: Vec::new(),
             map: HashMap::new(),
             chunks: Vec::new(),
+            history: History::new(),
+            events: Events::new(),
+            cache: ChunkCache::new(),
+            budget: 64 * 1024 * 1024,
+            generator: None,
+            biomes: None,
+            emitters: Emitters::new(),
+            light_changes: Vec::new(),
+            rewritten: false,
         }
     }
 
     pub fn load_wdfn(&mut self, path: PathBuf) {
-        let definition_regex = Regex::new(r#"\"(.+)\"\s+?(.)\((.+)\);"#).unwrap();
+        let definition_regex = Regex::new(r#"\"(.+)\"\s+?(.+);"#).unwrap(); // matches "name" c(1,2,3);
+        let attribute_regex = Regex::new(r"(\w)\(([^)]*)\)").unwrap(); // matches c(1,2,3)
 
         match File::open(&path) {
             Ok(mut file) => {
                 let mut buffer = "".to_owned();
                 file.read_to_string(&mut buffer).unwrap();
 
+                // definitions are numbered from 1 in file order, 0 is empty
+                self.definitions.clear();
                 for captured in definition_regex.captures_iter(&buffer) {
-                    //let name = captured.at(1).unwrap();
-                    //let attr_type = captured.at(2).unwrap();
-                    //let attr = captured.at(3).unwrap();
+                    let name = captured.at(1).unwrap();
+                    let attributes = captured.at(2).unwrap();
 
+                    let mut definition = Definition::new(name);
+                    for attribute in attribute_regex.captures_iter(attributes) {
+                        let attr_type = attribute.at(1).unwrap();
+                        let attr = attribute.at(2).unwrap();
 
+                        definition.attribute(attr_type, attr);
+                    }
+
+                    self.definitions.push(definition);
                 }
 
                 self.wdfn_file = path;
@@ -62,6 +220,21 @@ impl World {
         }
     }
 
+    pub fn definition(&self, id: u16) -> Option<&Definition> {
+        if id == 0 {
+            None
+        } else {
+            self.definitions.get(id as usize - 1)
+        }
+    }
+
+    // voxel id of the definition with this name
+    pub fn id(&self, name: &str) -> Option<u16> {
+        self.definitions.iter()
+            .position(|definition| definition.name == name)
+            .map(|index| index as u16 + 1)
+    }
+
     pub fn load_wrld(&mut self, path: PathBuf) {
         let region_regex = Regex::new(r"\^\((-?\d+),(-?\d+),(-?\d+)\):").unwrap(); // matches ^(0,0,0):
 
@@ -88,19 +261,507 @@ impl World {
     }
 
     pub fn load_chunk(&mut self, position: [i32; 3]) {
-        if let Some(location) = self.map.get(&position) {
+        if let Some(chunk) = self.cache.take(position) {
+            self.insert_chunk(chunk);
+        } else if let Some(location) = self.map.get(&position) {
             //println!("Chunk {:?} found at {:?}", position, location);
 
             match Chunk::from(&self.wrld_file, position, location.clone()) {
-                Some(chunk) => self.chunks.push(chunk),
+                Some(chunk) => self.insert_chunk(chunk),
                 None => { },
             }
+        } else if let Some(generator) = self.generator.clone() {
+            self.insert_chunk(generator.generate(position));
         } else {
             //println!("No chunk found at location: {:?}", position);
         }
     }
 
+    pub fn set_generator<G: ChunkGenerator + 'static>(&mut self, generator: G) {
+        let generator: Arc<ChunkGenerator> = Arc::new(generator);
+        self.generator = Some(generator);
+    }
+
+    // biome of a world voxel column, if the world has a biome map
+    pub fn biome_at(&self, x: i32, z: i32) -> Option<&Biome> {
+        match self.biomes {
+            Some(ref biomes) => biomes.biome_at(x, z),
+            None => None,
+        }
+    }
+
+    // whether `load_chunk` can come up with something for this position
+    pub fn exists(&self, position: [i32; 3]) -> bool {
+        self.generator.is_some() || self.map.contains_key(&position) || self.cache.contains(position)
+    }
+
+    // queues the chunk to be parsed on the loader's threads, see `integrate`
+    pub fn request_chunk(&mut self, loader: &ChunkLoader, position: [i32; 3]) -> bool {
+        if self.chunk(position).is_some() {
+            return false;
+        }
+
+        // recently unloaded chunks are cheap enough to decode right here
+        if let Some(chunk) = self.cache.take(position) {
+            self.insert_chunk(chunk);
+            return true;
+        }
+
+        match self.map.get(&position) {
+            Some(location) => loader.request(position, Source::Text {
+                file: self.wrld_file.clone(),
+                location: *location,
+            }),
+            None => match self.generator {
+                Some(ref generator) => loader.request(position, Source::Generate(generator.clone())),
+                None => false,
+            },
+        }
+    }
+
+    // adds the chunks the loader finished since last time, call once a frame
+    pub fn integrate(&mut self, loader: &ChunkLoader) -> usize {
+        // text requests would read at offsets from before the rewrite, they're asked for again
+        if self.rewritten {
+            let map = &self.map;
+            loader.retain(|position| !map.contains_key(&position));
+            self.rewritten = false;
+        }
+
+        let finished = loader.finished();
+        let count = finished.len();
+
+        for chunk in finished {
+            self.insert_chunk(chunk);
+        }
+
+        count
+    }
+
+    pub fn insert_chunk(&mut self, chunk: Chunk) {
+        let position = chunk.position();
+
+        // something else may have loaded it while it was being parsed
+        if self.chunk(position).is_some() {
+            return;
+        }
+
+        self.emitters.scan(&chunk, &self.definitions);
+        self.chunks.push(chunk);
+        self.mark_neighbours(position);
+        self.events.publish(WorldEvent::ChunkLoaded(position));
+    }
+
+    // the chunk is kept compressed in the cache until the memory budget runs out
     pub fn unload_chunk(&mut self, index: usize) {
-        self.chunks.remove(index);
+        let chunk = self.chunks.remove(index);
+        self.cache.insert(&chunk);
+        self.emitters.remove(chunk.position());
+        self.mark_neighbours(chunk.position());
+        self.events.publish(WorldEvent::ChunkUnloaded(chunk.position()));
+
+        self.enforce_budget();
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            loaded: self.chunks.len() * mem::size_of::<Chunk>(),
+            cached: self.cache.memory(),
+            budget: self.budget,
+        }
+    }
+
+    // evicts cached chunks until we fit the budget, writing back any with unsaved edits
+    pub fn enforce_budget(&mut self) {
+        let loaded = self.memory_usage().loaded;
+        let room = if self.budget > loaded { self.budget - loaded } else { 0 };
+
+        let evicted = self.cache.evict(room);
+        if !evicted.is_empty() {
+            self.write_out(&evicted);
+        }
+    }
+
+    // events published after this are queued until taken with `poll_events`
+    pub fn subscribe(&mut self) -> ObserverId {
+        self.events.subscribe()
+    }
+
+    pub fn unsubscribe(&mut self, id: ObserverId) {
+        self.events.unsubscribe(id);
+    }
+
+    pub fn poll_events(&mut self, id: ObserverId) -> Vec<WorldEvent> {
+        self.events.drain(id)
+    }
+
+    pub fn chunk_index(&self, position: [i32; 3]) -> Option<usize> {
+        self.chunks.iter().position(|chunk| chunk.position() == position)
+    }
+
+    pub fn chunk(&self, position: [i32; 3]) -> Option<&Chunk> {
+        self.chunks.iter().find(|chunk| chunk.position() == position)
+    }
+
+    pub fn chunk_mut(&mut self, position: [i32; 3]) -> Option<&mut Chunk> {
+        self.chunks.iter_mut().find(|chunk| chunk.position() == position)
+    }
+
+    // loaded chunks sharing a face with `position`, in FACES order
+    pub fn neighbours(&self, position: [i32; 3]) -> Neighbours {
+        let mut neighbours = [None; 6];
+        for (face, normal) in FACES.iter().enumerate() {
+            neighbours[face] = self.chunk([position[0] + normal[0],
+                                           position[1] + normal[1],
+                                           position[2] + normal[2]]);
+        }
+        neighbours
+    }
+
+    // loaded chunks touching `position` by a face, edge or corner, see `chunk::around_index`
+    pub fn around(&self, position: [i32; 3]) -> Around {
+        let mut around = [None; 27];
+        for x in -1..2 {
+            for y in -1..2 {
+                for z in -1..2 {
+                    if [x, y, z] != [0, 0, 0] {
+                        around[chunk::around_index([x, y, z])] = self.chunk([position[0] + x, position[1] + y, position[2] + z]);
+                    }
+                }
+            }
+        }
+        around
+    }
+
+    // lights chunks that were loaded or replaced from scratch and spreads the light around
+    // voxels changed since last time, call once a frame, returns the chunks relit
+    pub fn update_lighting(&mut self) -> usize {
+        let relight = self.chunks.iter()
+            .filter(|chunk| chunk.dirty.lighting)
+            .map(|chunk| chunk.position())
+            .collect::<Vec<_>>();
+        let changes = mem::replace(&mut self.light_changes, Vec::new());
+
+        if relight.is_empty() && changes.is_empty() {
+            return 0;
+        }
+
+        {
+            let mut lighter = Lighter::new(&mut self.chunks, &self.definitions);
+            for position in relight.iter() {
+                lighter.relight_chunk(*position);
+            }
+            for position in changes {
+                lighter.voxel_changed(position);
+            }
+        }
+
+        for chunk in self.chunks.iter_mut() {
+            chunk.dirty.lighting = false;
+        }
+
+        relight.len()
+    }
+
+    // None if the chunk holding the voxel isn't loaded
+    pub fn get_voxel(&self, position: [i32; 3]) -> Option<u16> {
+        let (chunk, local) = split(position);
+        self.chunk(chunk).map(|chunk| chunk.get(local))
+    }
+
+    // sets a voxel and records it in the history, returns the replaced id
+    pub fn set_voxel(&mut self, position: [i32; 3], id: u16) -> Option<u16> {
+        let old = self.write_voxel(position, id);
+
+        if let Some(old) = old {
+            self.history.record(Change {
+                position: position,
+                old: old,
+                new: id,
+            });
+
+            if old != id {
+                self.events.publish(WorldEvent::VoxelChanged {
+                    position: position,
+                    old: old,
+                    new: id,
+                });
+            }
+        }
+
+        old
+    }
+
+    // fills the inclusive box between min and max as a single undo step
+    pub fn fill(&mut self, min: [i32; 3], max: [i32; 3], id: u16) {
+        let nested = self.history.recording();
+        self.begin();
+
+        // one region event for the whole box rather than one per voxel
+        let mut changed = false;
+        for y in min[1]..max[1] + 1 {
+            for x in min[0]..max[0] + 1 {
+                for z in min[2]..max[2] + 1 {
+                    match self.write_voxel([x, y, z], id) {
+                        Some(old) if old != id => {
+                            self.history.record(Change {
+                                position: [x, y, z],
+                                old: old,
+                                new: id,
+                            });
+                            changed = true;
+                        },
+                        _ => (),
+                    }
+                }
+            }
+        }
+
+        // nothing loaded in the box was different, so there's nothing to remesh or save
+        if changed {
+            self.events.publish(WorldEvent::RegionChanged {
+                min: min,
+                max: max,
+            });
+        }
+
+        if !nested {
+            self.commit();
+        }
+    }
+
+    // groups following edits into one transaction until `commit`
+    pub fn begin(&mut self) {
+        self.history.begin();
+    }
+
+    pub fn commit(&mut self) {
+        if let Some(mut transaction) = self.history.end() {
+            let chunks = &self.chunks;
+            transaction.compact(self.history.snapshot_threshold, |position| {
+                chunks.iter().find(|chunk| chunk.position() == position).map(|chunk| chunk.data)
+            });
+
+            self.history.push(transaction);
+        }
+    }
+
+    pub fn undo(&mut self) -> bool {
+        self.commit();
+
+        match self.history.pop_undo() {
+            Some(transaction) => {
+                self.apply(&transaction, true);
+                self.history.push_redo(transaction);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        self.commit();
+
+        match self.history.pop_redo() {
+            Some(transaction) => {
+                self.apply(&transaction, false);
+                self.history.push_undo(transaction);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // whether there are edits that haven't been written with `save`
+    pub fn is_modified(&self) -> bool {
+        !self.history.is_saved()
+    }
+
+    // writes every changed chunk, loaded or cached, back into the wrld file
+    pub fn save(&mut self) {
+        let mut written = self.cache.dirty();
+        for chunk in self.chunks.iter() {
+            if chunk.dirty.save {
+                written.push(*chunk);
+            }
+        }
+
+        if !self.write_out(&written) {
+            return;
+        }
+
+        for chunk in self.chunks.iter_mut() {
+            chunk.dirty.save = false;
+        }
+        self.cache.mark_clean();
+
+        // an open transaction stays open, its edits count as unsaved once committed
+        self.history.mark_saved();
+    }
+
+    // rewrites the wrld file with `written` replacing what was there, other chunks are copied
+    fn write_out(&mut self, written: &[Chunk]) -> bool {
+        let mut buffer = "".to_owned();
+        if let Ok(mut file) = File::open(&self.wrld_file) {
+            file.read_to_string(&mut buffer).unwrap();
+        }
+
+        let mut locations = self.map.iter()
+            .map(|(position, location)| (*location as usize, *position))
+            .collect::<Vec<_>>();
+        locations.sort_by_key(|&(location, _)| location);
+
+        let mut content = "".to_owned();
+        for &(location, position) in locations.iter() {
+            if written.iter().any(|chunk| chunk.position() == position) {
+                continue;
+            }
+
+            // locations point past the header, copy the body up to its closing ^
+            let body = &buffer[location..];
+            let end = match body.find('^') {
+                Some(end) => end,
+                None => body.len(),
+            };
+
+            content = content + &format!("^({},{},{}):", position[0], position[1], position[2]);
+            content = content + body[..end].trim_right() + "^\r\n";
+        }
+
+        for chunk in written.iter() {
+            content = content + &chunk.write();
+        }
+
+        match File::create(&self.wrld_file) {
+            Ok(mut file) => {
+                file.write_all(content.as_bytes()).unwrap();
+            },
+            Err(e) => {
+                println!("{:?}", e);
+                return false;
+            }
+        }
+
+        self.map.clear();
+        let path = self.wrld_file.clone();
+        self.load_wrld(path);
+        self.rewritten = true;
+
+        true
+    }
+
+    // writes a voxel without touching the history
+    fn write_voxel(&mut self, position: [i32; 3], id: u16) -> Option<u16> {
+        let (chunk, local) = split(position);
+        // the light around the voxel is fixed up from `light_changes`, not the whole chunk
+        let old = match self.chunk_mut(chunk) {
+            Some(chunk) => {
+                let lighting = chunk.dirty.lighting;
+                let old = chunk.set(local, id);
+                chunk.dirty.lighting = lighting;
+                old
+            },
+            None => return None,
+        };
+
+        if old != id {
+            self.emitters.set(position, id, &self.definitions);
+            self.light_changes.push(position);
+        }
+
+        // faces of the chunks around us can depend on border voxels, through occlusion
+        // that includes the chunks past an edge or corner
+        if old != id && Chunk::on_border(local) {
+            let step = |l: usize| if l == 0 { -1 } else if l == SIZE - 1 { 1 } else { 0 };
+            let steps = [step(local[0]), step(local[1]), step(local[2])];
+
+            for x in 0..2 {
+                for y in 0..2 {
+                    for z in 0..2 {
+                        let offset = [steps[0] * x, steps[1] * y, steps[2] * z];
+                        if offset == [0, 0, 0] {
+                            continue;
+                        }
+
+                        let neighbour = [chunk[0] + offset[0], chunk[1] + offset[1], chunk[2] + offset[2]];
+                        if let Some(neighbour) = self.chunk_mut(neighbour) {
+                            neighbour.dirty.mesh = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        Some(old)
+    }
+
+    // marks the chunks around `position` for remeshing, edges and corners included for
+    // their occlusion, their light is fixed up when the chunk itself is lit
+    fn mark_neighbours(&mut self, position: [i32; 3]) {
+        for x in -1..2 {
+            for y in -1..2 {
+                for z in -1..2 {
+                    if [x, y, z] == [0, 0, 0] {
+                        continue;
+                    }
+
+                    if let Some(neighbour) = self.chunk_mut([position[0] + x, position[1] + y, position[2] + z]) {
+                        neighbour.dirty.mesh = true;
+                    }
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, transaction: &Transaction, reverse: bool) {
+        let apply_edit = |world: &mut World, edit: &Edit| {
+            match *edit {
+                Edit::Voxel(ref change) => {
+                    let id = if reverse { change.old } else { change.new };
+                    let (chunk, _) = split(change.position);
+                    if world.chunk(chunk).is_none() {
+                        world.load_chunk(chunk);
+                    }
+
+                    let old = if reverse { change.new } else { change.old };
+                    if world.write_voxel(change.position, id).is_some() {
+                        world.events.publish(WorldEvent::VoxelChanged {
+                            position: change.position,
+                            old: old,
+                            new: id,
+                        });
+                    }
+                },
+                Edit::Snapshot { chunk, ref old, ref new } => {
+                    if world.chunk(chunk).is_none() {
+                        world.load_chunk(chunk);
+                    }
+
+                    if let Some(loaded) = world.chunk_mut(chunk) {
+                        loaded.replace(if reverse { **old } else { **new });
+                    }
+                    if let Some(loaded) = world.chunks.iter().find(|loaded| loaded.position() == chunk) {
+                        world.emitters.scan(loaded, &world.definitions);
+                    }
+                    world.mark_neighbours(chunk);
+
+                    let size = SIZE as i32;
+                    world.events.publish(WorldEvent::RegionChanged {
+                        min: [chunk[0] * size, chunk[1] * size, chunk[2] * size],
+                        max: [chunk[0] * size + size - 1, chunk[1] * size + size - 1, chunk[2] * size + size - 1],
+                    });
+                },
+            }
+        };
+
+        if reverse {
+            for edit in transaction.edits.iter().rev() {
+                apply_edit(self, edit);
+            }
+        } else {
+            for edit in transaction.edits.iter() {
+                apply_edit(self, edit);
+            }
+        }
     }
 }
\ No newline at end of file
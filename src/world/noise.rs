@@ -0,0 +1,145 @@
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+// seeded rng, the same seed always gives the same sequence
+pub fn rng(seed: u64) -> XorShiftRng {
+    XorShiftRng::from_seed([
+        seed as u32 ^ 0x9e3779b9,
+        (seed >> 32) as u32 ^ 0x7f4a7c15,
+        0x6a09e667,
+        0xbb67ae85,
+    ])
+}
+
+// seed for something at a lattice position, like a chunk or a cell
+pub fn hash(seed: u64, position: [i32; 3]) -> u64 {
+    let mut h = seed ^ 0xcbf29ce484222325;
+    for &p in position.iter() {
+        h ^= p as u32 as u64;
+        h = h.wrapping_mul(0x100000001b3);
+        h ^= h >> 29;
+    }
+    h
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+fn grad2(hash: u8, x: f32, z: f32) -> f32 {
+    match hash & 7 {
+        0 => x + z,
+        1 => x - z,
+        2 => -x + z,
+        3 => -x - z,
+        4 => x,
+        5 => -x,
+        6 => z,
+        _ => -z,
+    }
+}
+
+fn grad3(hash: u8, x: f32, y: f32, z: f32) -> f32 {
+    match hash & 15 {
+        0 | 12 => x + y,
+        1 | 14 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 | 13 => -y + z,
+        10 => y - z,
+        _ => -y - z,
+    }
+}
+
+// gradient noise, roughly in -1..1
+#[derive(Clone, Debug)]
+pub struct Noise {
+    perm: Vec<u8>, // 256 shuffled values repeated twice
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Noise {
+        let mut table = (0..256).map(|i| i as u8).collect::<Vec<u8>>();
+        rng(seed).shuffle(&mut table);
+
+        let mut perm = table.clone();
+        perm.extend_from_slice(&table);
+
+        Noise {
+            perm: perm,
+        }
+    }
+
+    fn p(&self, i: i32) -> usize {
+        self.perm[(i & 255) as usize] as usize
+    }
+
+    pub fn get2(&self, x: f32, z: f32) -> f32 {
+        let (fx, fz) = (x.floor(), z.floor());
+        let (ix, iz) = (fx as i32, fz as i32);
+        let (x, z) = (x - fx, z - fz);
+        let (u, v) = (fade(x), fade(z));
+
+        let a = self.p(ix) + (iz & 255) as usize;
+        let b = self.p(ix + 1) + (iz & 255) as usize;
+
+        lerp(v,
+             lerp(u, grad2(self.perm[a], x, z), grad2(self.perm[b], x - 1.0, z)),
+             lerp(u, grad2(self.perm[a + 1], x, z - 1.0), grad2(self.perm[b + 1], x - 1.0, z - 1.0)))
+    }
+
+    pub fn get3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (fx, fy, fz) = (x.floor(), y.floor(), z.floor());
+        let (ix, iy, iz) = (fx as i32, fy as i32, fz as i32);
+        let (x, y, z) = (x - fx, y - fy, z - fz);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+
+        let a = self.p(ix) + (iy & 255) as usize;
+        let aa = self.perm[a] as usize + (iz & 255) as usize;
+        let ab = self.perm[a + 1] as usize + (iz & 255) as usize;
+        let b = self.p(ix + 1) + (iy & 255) as usize;
+        let ba = self.perm[b] as usize + (iz & 255) as usize;
+        let bb = self.perm[b + 1] as usize + (iz & 255) as usize;
+
+        lerp(w,
+             lerp(v,
+                  lerp(u, grad3(self.perm[aa], x, y, z), grad3(self.perm[ba], x - 1.0, y, z)),
+                  lerp(u, grad3(self.perm[ab], x, y - 1.0, z), grad3(self.perm[bb], x - 1.0, y - 1.0, z))),
+             lerp(v,
+                  lerp(u, grad3(self.perm[aa + 1], x, y, z - 1.0), grad3(self.perm[ba + 1], x - 1.0, y, z - 1.0)),
+                  lerp(u, grad3(self.perm[ab + 1], x, y - 1.0, z - 1.0), grad3(self.perm[bb + 1], x - 1.0, y - 1.0, z - 1.0))))
+    }
+
+    // octaves of noise, each at twice the frequency and `persistence` times the amplitude
+    pub fn fractal2(&self, x: f32, z: f32, octaves: u32, persistence: f32) -> f32 {
+        let (mut sum, mut amplitude, mut frequency, mut total) = (0.0, 1.0, 1.0, 0.0);
+        for _ in 0..octaves {
+            sum += self.get2(x * frequency, z * frequency) * amplitude;
+            total += amplitude;
+            amplitude *= persistence;
+            frequency *= 2.0;
+        }
+
+        if total > 0.0 { sum / total } else { 0.0 }
+    }
+
+    pub fn fractal3(&self, x: f32, y: f32, z: f32, octaves: u32, persistence: f32) -> f32 {
+        let (mut sum, mut amplitude, mut frequency, mut total) = (0.0, 1.0, 1.0, 0.0);
+        for _ in 0..octaves {
+            sum += self.get3(x * frequency, y * frequency, z * frequency) * amplitude;
+            total += amplitude;
+            amplitude *= persistence;
+            frequency *= 2.0;
+        }
+
+        if total > 0.0 { sum / total } else { 0.0 }
+    }
+}
@@ -0,0 +1,457 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+
+use rand::Rng;
+
+use super::split;
+use super::chunk::{Chunk, SIZE};
+use super::generator::ChunkGenerator;
+use super::noise;
+
+// how far along generation a chunk is, in the order the stages run
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Status {
+    Terrain,
+    Caves,
+    Ores,
+    Decorated,
+}
+
+// a chunk and the 26 around it, so stages can read and write across borders
+pub struct Region {
+    center: [i32; 3],
+    chunks: Vec<Chunk>, // indexed by `Region::index`
+}
+
+impl Region {
+    fn index(offset: [i32; 3]) -> usize {
+        ((offset[0] + 1) * 9 + (offset[1] + 1) * 3 + (offset[2] + 1)) as usize
+    }
+
+    fn offsets() -> Vec<[i32; 3]> {
+        let mut offsets = Vec::new();
+        for x in -1..2 {
+            for y in -1..2 {
+                for z in -1..2 {
+                    offsets.push([x, y, z]);
+                }
+            }
+        }
+        offsets
+    }
+
+    // only the center is real, the chunks around it read as empty
+    fn alone(chunk: Chunk) -> Region {
+        let center = chunk.position();
+        let chunks = Region::offsets().into_iter()
+            .map(|offset| if offset == [0, 0, 0] {
+                chunk
+            } else {
+                Chunk::new([center[0] + offset[0], center[1] + offset[1], center[2] + offset[2]])
+            })
+            .collect();
+
+        Region {
+            center: center,
+            chunks: chunks,
+        }
+    }
+
+    pub fn center(&self) -> [i32; 3] {
+        self.center
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunks[Region::index([0, 0, 0])]
+    }
+
+    pub fn chunk_mut(&mut self) -> &mut Chunk {
+        &mut self.chunks[Region::index([0, 0, 0])]
+    }
+
+    fn offset(&self, position: [i32; 3]) -> Option<(usize, [usize; 3])> {
+        let (chunk, local) = split(position);
+        let offset = [chunk[0] - self.center[0], chunk[1] - self.center[1], chunk[2] - self.center[2]];
+
+        if offset.iter().all(|&o| o >= -1 && o <= 1) {
+            Some((Region::index(offset), local))
+        } else {
+            None
+        }
+    }
+
+    // world voxel position, None outside the region
+    pub fn get(&self, position: [i32; 3]) -> Option<u16> {
+        self.offset(position).map(|(index, local)| self.chunks[index].get(local))
+    }
+
+    // writes outside the region are dropped
+    pub fn set(&mut self, position: [i32; 3], id: u16) -> bool {
+        match self.offset(position) {
+            Some((index, local)) => {
+                self.chunks[index].set(local, id);
+                true
+            },
+            None => false,
+        }
+    }
+
+    // world position of the center chunk's minimum corner
+    pub fn origin(&self) -> [i32; 3] {
+        let size = SIZE as i32;
+        [self.center[0] * size, self.center[1] * size, self.center[2] * size]
+    }
+}
+
+pub trait Stage: fmt::Debug + Send + Sync {
+    // the status a chunk has after this stage
+    fn status(&self) -> Status;
+
+    // whether the stage reads or writes neighbouring chunks, if not it only needs the center
+    fn reach(&self) -> bool;
+
+    fn run(&self, seed: u64, region: &mut Region);
+}
+
+// intermediate results by position and how many stages they've been through
+#[derive(Debug)]
+struct Cache {
+    chunks: HashMap<([i32; 3], usize), Chunk>, // only the levels a reaching stage reads
+    writes: HashMap<([i32; 3], usize), Vec<([i32; 3], u16)>>, // what a reaching stage run around a chunk changed
+    levels: HashMap<[i32; 3], usize>, // the most stages each chunk has been through
+    last: [i32; 3], // most recently generated position, roughly where the streaming is
+}
+
+// generates chunks through a series of stages
+//
+// every chunk is a function of the seed and its position alone: a stage that reaches into
+// neighbours is run around each chunk next to this one as well, and only what lands in this
+// one is kept, so a chunk comes out the same whenever it's generated and whatever was
+// generated before
+#[derive(Debug)]
+pub struct Pipeline {
+    seed: u64,
+    base: Arc<ChunkGenerator>,
+    stages: Vec<Box<Stage>>, // sorted by status
+    cache: Mutex<Cache>,
+    pub capacity: usize, // entries of each kind kept before the ones furthest from `last` go, see `fit`
+}
+
+impl Pipeline {
+    pub fn new<G: ChunkGenerator + 'static>(seed: u64, base: G) -> Pipeline {
+        Pipeline {
+            seed: seed,
+            base: Arc::new(base),
+            stages: Vec::new(),
+            cache: Mutex::new(Cache {
+                chunks: HashMap::new(),
+                writes: HashMap::new(),
+                levels: HashMap::new(),
+                last: [0, 0, 0],
+            }),
+            capacity: 1024,
+        }
+    }
+
+    pub fn add_stage<S: Stage + 'static>(&mut self, stage: S) {
+        self.stages.push(Box::new(stage));
+        self.stages.sort_by_key(|stage| stage.status());
+    }
+
+    // sizes the cache to hold what every reaching stage needs for chunks within `radius`
+    // of where generation happens and the ring just past it, so streaming never regenerates
+    pub fn fit(&mut self, radius: i32) {
+        let r = (radius + 2) as usize;
+        let sphere = r * r * r * 4 + 1;
+        let reaching = self.stages.iter().filter(|stage| stage.reach()).count();
+        self.capacity = sphere * reaching.max(1);
+    }
+
+    // how far along generation the chunk at `position` has got, None if it hasn't started
+    pub fn status(&self, position: [i32; 3]) -> Option<Status> {
+        match self.cache.lock().unwrap().levels.get(&position) {
+            Some(&0) => Some(Status::Terrain),
+            Some(&level) => Some(self.stages[level - 1].status()),
+            None => None,
+        }
+    }
+
+    // intermediate chunks held on to for neighbours that still need them
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().chunks.len()
+    }
+
+    // whether the chunks after `level` stages are read by the next stage around other chunks
+    fn shared(&self, level: usize) -> bool {
+        level < self.stages.len() && self.stages[level].reach()
+    }
+
+    // the chunk after the first `level` stages, the lock is only held to look things up and store them
+    fn chunk_at(&self, position: [i32; 3], level: usize) -> Chunk {
+        if let Some(chunk) = self.cache.lock().unwrap().chunks.get(&(position, level)) {
+            return *chunk;
+        }
+
+        let chunk = if level == 0 {
+            self.base.generate(position)
+        } else {
+            let stage = &self.stages[level - 1];
+            let previous = self.chunk_at(position, level - 1);
+
+            if stage.reach() {
+                self.gather(position, level - 1, previous)
+            } else {
+                let mut region = Region::alone(previous);
+                stage.run(self.seed, &mut region);
+                *region.chunk()
+            }
+        };
+
+        let mut guard = self.cache.lock().unwrap();
+        let cache = &mut *guard;
+        let reached = cache.levels.entry(position).or_insert(level);
+        if *reached < level {
+            *reached = level;
+        }
+
+        if self.shared(level) {
+            cache.chunks.insert((position, level), chunk);
+            evict(&mut cache.chunks, self.capacity, cache.last);
+        }
+
+        chunk
+    }
+
+    // applies the writes of the reaching stage after `level` from every chunk that could reach
+    // `position`, in world order so overlaps always resolve the same way: the first write wins
+    fn gather(&self, position: [i32; 3], level: usize, previous: Chunk) -> Chunk {
+        let mut result = previous;
+
+        for offset in Region::offsets() {
+            let center = [position[0] + offset[0], position[1] + offset[1], position[2] + offset[2]];
+
+            for (voxel, id) in self.writes(center, level) {
+                let (chunk, local) = split(voxel);
+                if chunk == position && result.get(local) == previous.get(local) {
+                    result.set(local, id);
+                }
+            }
+        }
+
+        result
+    }
+
+    // every voxel the reaching stage after `level` changes when run around `center`, each run
+    // happens once and is shared by all 27 chunks it can write to
+    fn writes(&self, center: [i32; 3], level: usize) -> Vec<([i32; 3], u16)> {
+        if let Some(writes) = self.cache.lock().unwrap().writes.get(&(center, level)) {
+            return writes.clone();
+        }
+
+        let before = Region::offsets().into_iter()
+            .map(|o| self.chunk_at([center[0] + o[0], center[1] + o[1], center[2] + o[2]], level))
+            .collect::<Vec<_>>();
+        let mut region = Region {
+            center: center,
+            chunks: before.clone(),
+        };
+        self.stages[level].run(self.seed, &mut region);
+
+        let mut writes = Vec::new();
+        for (before, after) in before.iter().zip(region.chunks.iter()) {
+            let origin = before.position();
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    for z in 0..SIZE {
+                        let id = after.get([x, y, z]);
+                        if id != before.get([x, y, z]) {
+                            let size = SIZE as i32;
+                            writes.push(([origin[0] * size + x as i32, origin[1] * size + y as i32, origin[2] * size + z as i32], id));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut guard = self.cache.lock().unwrap();
+        let cache = &mut *guard;
+        cache.writes.insert((center, level), writes.clone());
+        evict(&mut cache.writes, self.capacity, cache.last);
+
+        writes
+    }
+}
+
+// drops a quarter at a time, furthest from `last` first, so this doesn't happen on every insert
+fn evict<V>(entries: &mut HashMap<([i32; 3], usize), V>, capacity: usize, last: [i32; 3]) {
+    if entries.len() <= capacity {
+        return;
+    }
+
+    let mut keys = entries.keys().cloned().collect::<Vec<_>>();
+    keys.sort_by_key(|&(position, _)| -distance(position, last));
+
+    let excess = entries.len() - capacity * 3 / 4;
+    for key in keys.into_iter().take(excess) {
+        entries.remove(&key);
+    }
+}
+
+fn distance(a: [i32; 3], b: [i32; 3]) -> i32 {
+    let (x, y, z) = (a[0] - b[0], a[1] - b[1], a[2] - b[2]);
+    x * x + y * y + z * z
+}
+
+impl ChunkGenerator for Pipeline {
+    fn generate(&self, position: [i32; 3]) -> Chunk {
+        self.cache.lock().unwrap().last = position;
+
+        let mut chunk = self.chunk_at(position, self.stages.len());
+
+        // can be made again from the seed, so only later edits need saving
+        chunk.dirty.save = false;
+        chunk
+    }
+}
+
+// scatters blobs of ore through one material, inside a band of heights
+#[derive(Clone, Debug)]
+pub struct OreStage {
+    pub ore: u16,
+    pub host: u16, // only this id is replaced
+    pub veins: u32, // attempts per chunk
+    pub size: u32, // voxels per vein
+    pub min: i32, // world height band
+    pub max: i32,
+}
+
+impl OreStage {
+    pub fn new(ore: u16, host: u16) -> OreStage {
+        OreStage {
+            ore: ore,
+            host: host,
+            veins: 8,
+            size: 6,
+            min: -64,
+            max: 0,
+        }
+    }
+}
+
+impl Stage for OreStage {
+    fn status(&self) -> Status {
+        Status::Ores
+    }
+
+    fn reach(&self) -> bool {
+        false
+    }
+
+    fn run(&self, seed: u64, region: &mut Region) {
+        let origin = region.origin();
+        if origin[1] > self.max || origin[1] + SIZE as i32 <= self.min {
+            return;
+        }
+
+        let mut rng = noise::rng(noise::hash(seed ^ self.ore as u64, region.center()));
+        let chunk = region.chunk_mut();
+
+        for _ in 0..self.veins {
+            let mut local = [rng.gen_range(0, SIZE), rng.gen_range(0, SIZE), rng.gen_range(0, SIZE)];
+
+            for _ in 0..self.size {
+                let y = origin[1] + local[1] as i32;
+                if y >= self.min && y <= self.max && chunk.get(local) == self.host {
+                    chunk.set(local, self.ore);
+                }
+
+                // wander to a neighbouring voxel, staying in the chunk
+                let axis = rng.gen_range(0, 3);
+                if rng.gen() {
+                    if local[axis] + 1 < SIZE {
+                        local[axis] += 1;
+                    }
+                } else if local[axis] > 0 {
+                    local[axis] -= 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pipeline, Region, Stage, Status};
+    use world::generator::{ChunkGenerator, TerrainGenerator};
+
+    // a line of voxels from the middle of each chunk two chunks along x, so every chunk
+    // is written by the one before it and overlaps what its own line writes
+    #[derive(Debug)]
+    struct Beams;
+
+    impl Stage for Beams {
+        fn status(&self) -> Status {
+            Status::Decorated
+        }
+
+        fn reach(&self) -> bool {
+            true
+        }
+
+        fn run(&self, _: u64, region: &mut Region) {
+            let origin = region.origin();
+            let center = region.center();
+            let id = 10 + ((center[0] % 3 + 3) % 3) as u16;
+            for x in 8..32 {
+                region.set([origin[0] + x, origin[1] + 8, origin[2] + 8], id);
+            }
+        }
+    }
+
+    fn pipeline() -> Pipeline {
+        let mut pipeline = Pipeline::new(3, TerrainGenerator::new(3));
+        pipeline.add_stage(Beams);
+        pipeline
+    }
+
+    #[test]
+    fn order_does_not_matter() {
+        let alone = pipeline().generate([0, 0, 0]);
+
+        let after = pipeline();
+        for position in [[1, 0, 0], [-1, 0, 0], [0, 0, 1], [-1, 1, -1]].iter() {
+            after.generate(*position);
+        }
+        let after = after.generate([0, 0, 0]);
+
+        assert_eq!(alone.encode(), after.encode());
+
+        // the beam from the chunk before runs into this one and the first write wins
+        assert_eq!(alone.get([4, 8, 8]), 12);
+        assert_eq!(alone.get([12, 8, 8]), 12);
+    }
+
+    #[test]
+    fn same_chunk_twice() {
+        let pipeline = pipeline();
+        let first = pipeline.generate([2, 0, -1]);
+        pipeline.generate([3, 0, -1]);
+        assert_eq!(pipeline.generate([2, 0, -1]).encode(), first.encode());
+    }
+
+    #[test]
+    fn tracks_status() {
+        let pipeline = pipeline();
+        assert_eq!(pipeline.status([0, 0, 0]), None);
+
+        pipeline.generate([0, 0, 0]);
+        assert_eq!(pipeline.status([0, 0, 0]), Some(Status::Decorated));
+
+        // the chunks around were only taken as far as the beams needed, out to the
+        // regions of the chunks next to this one
+        assert_eq!(pipeline.status([1, 0, 0]), Some(Status::Terrain));
+        assert_eq!(pipeline.status([2, 0, 0]), Some(Status::Terrain));
+        assert_eq!(pipeline.status([3, 0, 0]), None);
+    }
+}
@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+
+use rand::Rng;
+
+use super::World;
+use super::chunk::SIZE;
+use super::noise;
+use super::pipeline::{Region, Stage, Status};
+
+// a small structure of voxels placed relative to an anchor at its base
+#[derive(Clone, Debug)]
+pub struct Prefab {
+    pub voxels: Vec<([i32; 3], u16)>, // offset from the anchor and id, never 0
+}
+
+impl Prefab {
+    // every non-empty voxel in the wrld file, anchored at the bottom center
+    pub fn from_wrld(path: PathBuf) -> Prefab {
+        let mut world = World::new();
+        world.load_wrld(path);
+
+        let positions = world.map.keys().cloned().collect::<Vec<_>>();
+        for position in positions {
+            world.load_chunk(position);
+        }
+
+        let size = SIZE as i32;
+        let mut voxels = Vec::new();
+        for chunk in world.chunks.iter() {
+            let origin = chunk.position();
+            for y in 0..SIZE {
+                for x in 0..SIZE {
+                    for z in 0..SIZE {
+                        let id = chunk.get([x, y, z]);
+                        if id != 0 {
+                            voxels.push(([origin[0] * size + x as i32,
+                                          origin[1] * size + y as i32,
+                                          origin[2] * size + z as i32], id));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut prefab = Prefab {
+            voxels: voxels,
+        };
+        prefab.anchor();
+        prefab
+    }
+
+    // swaps ids for the ones the world's wdfn uses, anything mapped to 0 is dropped
+    pub fn remap(&self, ids: &[(u16, u16)]) -> Prefab {
+        let voxels = self.voxels.iter()
+            .map(|&(offset, id)| match ids.iter().find(|&&(from, _)| from == id) {
+                Some(&(_, to)) => (offset, to),
+                None => (offset, id),
+            })
+            .filter(|&(_, id)| id != 0)
+            .collect();
+
+        let mut prefab = Prefab {
+            voxels: voxels,
+        };
+        prefab.anchor();
+        prefab
+    }
+
+    // a trunk with a round blob of leaves on top
+    pub fn tree(trunk: u16, leaves: u16, height: i32) -> Prefab {
+        let mut voxels = Vec::new();
+
+        for y in 0..height {
+            voxels.push(([0, y, 0], trunk));
+        }
+
+        let radius = 2;
+        for y in -radius..radius + 1 {
+            for x in -radius..radius + 1 {
+                for z in -radius..radius + 1 {
+                    if x * x + y * y + z * z <= radius * radius + 1 && !(x == 0 && z == 0 && y < 0) {
+                        voxels.push(([x, height + y, z], leaves));
+                    }
+                }
+            }
+        }
+
+        Prefab {
+            voxels: voxels,
+        }
+    }
+
+    // moves the voxels so the lowest layer is at 0 and x/z are centered
+    fn anchor(&mut self) {
+        if self.voxels.is_empty() {
+            return;
+        }
+
+        let mut min = self.voxels[0].0;
+        let mut max = self.voxels[0].0;
+        for &(position, _) in self.voxels.iter() {
+            for i in 0..3 {
+                min[i] = min[i].min(position[i]);
+                max[i] = max[i].max(position[i]);
+            }
+        }
+
+        let anchor = [(min[0] + max[0]) / 2, min[1], (min[2] + max[2]) / 2];
+        for voxel in self.voxels.iter_mut() {
+            for i in 0..3 {
+                voxel.0[i] -= anchor[i];
+            }
+        }
+    }
+
+    // only fills empty space, anything outside the region is cut off
+    pub fn place(&self, region: &mut Region, anchor: [i32; 3]) {
+        for &(offset, id) in self.voxels.iter() {
+            let position = [anchor[0] + offset[0], anchor[1] + offset[1], anchor[2] + offset[2]];
+            if region.get(position) == Some(0) {
+                region.set(position, id);
+            }
+        }
+    }
+}
+
+// places prefabs on top of the ground material
+#[derive(Clone, Debug)]
+pub struct DecorationStage {
+    pub prefabs: Vec<Prefab>,
+    pub ground: u16, // prefabs only stand on this id
+    pub attempts: u32, // columns tried per chunk
+}
+
+impl DecorationStage {
+    pub fn new(ground: u16) -> DecorationStage {
+        DecorationStage {
+            prefabs: Vec::new(),
+            ground: ground,
+            attempts: 2,
+        }
+    }
+
+    pub fn with(mut self, prefab: Prefab) -> DecorationStage {
+        self.prefabs.push(prefab);
+        self
+    }
+}
+
+impl Stage for DecorationStage {
+    fn status(&self) -> Status {
+        Status::Decorated
+    }
+
+    fn reach(&self) -> bool {
+        true
+    }
+
+    fn run(&self, seed: u64, region: &mut Region) {
+        if self.prefabs.is_empty() {
+            return;
+        }
+
+        let mut rng = noise::rng(noise::hash(seed ^ 0xdec0, region.center()));
+        let origin = region.origin();
+
+        for _ in 0..self.attempts {
+            let (x, z) = (rng.gen_range(0, SIZE), rng.gen_range(0, SIZE));
+            let prefab = &self.prefabs[rng.gen_range(0, self.prefabs.len())];
+
+            // highest ground in this column of the chunk with air above it
+            for y in (0..SIZE).rev() {
+                let position = [origin[0] + x as i32, origin[1] + y as i32, origin[2] + z as i32];
+                let above = [position[0], position[1] + 1, position[2]];
+
+                if region.get(position) == Some(self.ground) && region.get(above) == Some(0) {
+                    prefab.place(region, above);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashSet;
+
+use cgmath::Vector3;
+
+use super::World;
+use super::chunk::{SCALE, SIZE};
+use super::loader::ChunkLoader;
+
+// chunk containing a point in render units, like Camera::position
+pub fn chunk_at(point: Vector3<f32>) -> [i32; 3] {
+    let size = SCALE * SIZE as f32;
+    [
+        (point.x / size).floor() as i32,
+        (point.y / size).floor() as i32,
+        (point.z / size).floor() as i32,
+    ]
+}
+
+fn distance(a: [i32; 3], b: [i32; 3]) -> i32 {
+    let (x, y, z) = (a[0] - b[0], a[1] - b[1], a[2] - b[2]);
+    x * x + y * y + z * z
+}
+
+// keeps the chunks around a point loaded
+#[derive(Debug)]
+pub struct Streamer {
+    pub radius: i32, // chunks within this distance are loaded
+    pub hysteresis: i32, // extra distance a chunk can drift before it is unloaded
+    center: Option<[i32; 3]>,
+}
+
+impl Streamer {
+    pub fn new(radius: i32, hysteresis: i32) -> Streamer {
+        Streamer {
+            radius: radius,
+            hysteresis: hysteresis,
+            center: None,
+        }
+    }
+
+    pub fn in_range(&self, center: [i32; 3], position: [i32; 3]) -> bool {
+        distance(center, position) <= self.radius * self.radius
+    }
+
+    pub fn out_of_range(&self, center: [i32; 3], position: [i32; 3]) -> bool {
+        let outer = self.radius + self.hysteresis;
+        distance(center, position) > outer * outer
+    }
+
+    // positions in range that the world can load, closest first
+    fn wanted(&self, world: &World, center: [i32; 3]) -> Vec<[i32; 3]> {
+        let mut wanted = if world.generator.is_some() {
+            // anything can be generated, so every position in range
+            let mut positions = Vec::new();
+            let r = self.radius;
+            for x in -r..r + 1 {
+                for y in -r..r + 1 {
+                    for z in -r..r + 1 {
+                        let position = [center[0] + x, center[1] + y, center[2] + z];
+                        if self.in_range(center, position) {
+                            positions.push(position);
+                        }
+                    }
+                }
+            }
+            positions
+        } else {
+            world.map.keys()
+                .filter(|position| self.in_range(center, **position))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        wanted.sort_by_key(|position| distance(center, *position));
+        wanted
+    }
+
+    // loads the chunks within `radius` right away, for startup before there is anything
+    // to draw, the rest of the range is left to the loader
+    pub fn prime(&mut self, world: &mut World, point: Vector3<f32>, radius: i32) {
+        let center = chunk_at(point);
+        let wanted = self.wanted(world, center);
+
+        for position in wanted {
+            if distance(center, position) > radius * radius {
+                break;
+            }
+
+            if world.chunk(position).is_none() {
+                world.load_chunk(position);
+            }
+        }
+
+        self.center = Some(center);
+    }
+
+    pub fn update(&mut self, world: &mut World, loader: &ChunkLoader, point: Vector3<f32>) {
+        let center = chunk_at(point);
+        let moved = self.center != Some(center);
+        self.center = Some(center);
+
+        loader.set_center(center);
+
+        if moved {
+            loader.retain(|position| !self.out_of_range(center, position));
+        }
+
+        // the queue only holds so many, so whatever didn't fit is asked for again
+        // every frame, closest first, until it's full
+        let loaded = world.chunks.iter().map(|chunk| chunk.position()).collect::<HashSet<_>>();
+        for position in self.wanted(world, center) {
+            if loaded.contains(&position) || loader.is_requested(position) {
+                continue;
+            }
+
+            if !world.request_chunk(loader, position) {
+                break;
+            }
+        }
+
+        let leaving = world.chunks.iter()
+            .filter(|chunk| self.out_of_range(center, chunk.position()))
+            .map(|chunk| chunk.position())
+            .collect::<Vec<_>>();
+
+        // unloaded chunks keep their edits in the world's cache until they're evicted
+        for position in leaving {
+            if let Some(index) = world.chunk_index(position) {
+                world.unload_chunk(index);
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bit_set::BitSet;
+use collision::{Frustum, Relation};
+
+use super::Definition;
+use super::chunk::{self, Chunk, FACES, SIZE};
+
+fn opposite(face: usize) -> usize {
+    // FACES lists each direction next to its opposite
+    face ^ 1
+}
+
+// which faces of a chunk can see each other through its non-opaque voxels
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Connectivity {
+    bits: u64, // bit a * 6 + b is set if face a connects to face b
+}
+
+impl Connectivity {
+    pub fn connects(&self, a: usize, b: usize) -> bool {
+        self.bits & (1 << (a * 6 + b)) != 0
+    }
+
+    fn connect(&mut self, faces: u8) {
+        for a in 0..6 {
+            for b in 0..6 {
+                if faces & (1 << a) != 0 && faces & (1 << b) != 0 {
+                    self.bits |= 1 << (a * 6 + b);
+                }
+            }
+        }
+    }
+
+    // flood fills every pocket of non-opaque voxels and connects the faces each one touches
+    pub fn new(chunk: &Chunk, definitions: &[Definition]) -> Connectivity {
+        let index = |local: [usize; 3]| (local[1] * SIZE + local[0]) * SIZE + local[2];
+        let mut connectivity = Connectivity { bits: 0 };
+        let mut seen = BitSet::with_capacity(SIZE * SIZE * SIZE);
+        let mut stack = Vec::new();
+
+        for x in 0..SIZE {
+            for y in 0..SIZE {
+                for z in 0..SIZE {
+                    let start = [x, y, z];
+                    if seen.contains(index(start)) || chunk::is_opaque(definitions, chunk.get(start)) {
+                        continue;
+                    }
+
+                    let mut faces = 0u8;
+                    seen.insert(index(start));
+                    stack.push(start);
+
+                    while let Some(local) = stack.pop() {
+                        for (face, normal) in FACES.iter().enumerate() {
+                            let mut next = [0; 3];
+                            let mut border = false;
+                            for i in 0..3 {
+                                let p = local[i] as i32 + normal[i];
+                                if p < 0 || p >= SIZE as i32 {
+                                    border = true;
+                                }
+                                next[i] = p as usize;
+                            }
+
+                            if border {
+                                faces |= 1 << face;
+                                continue;
+                            }
+
+                            if !seen.contains(index(next)) && !chunk::is_opaque(definitions, chunk.get(next)) {
+                                seen.insert(index(next));
+                                stack.push(next);
+                            }
+                        }
+                    }
+
+                    connectivity.connect(faces);
+                }
+            }
+        }
+
+        connectivity
+    }
+}
+
+// connectivity of every loaded chunk, walked from the camera to find what could be seen
+#[derive(Clone, Debug)]
+pub struct VisibilityGraph {
+    chunks: HashMap<[i32; 3], Connectivity>,
+}
+
+impl VisibilityGraph {
+    pub fn new() -> VisibilityGraph {
+        VisibilityGraph {
+            chunks: HashMap::new(),
+        }
+    }
+
+    // call whenever the chunk's voxels change
+    pub fn update(&mut self, chunk: &Chunk, definitions: &[Definition]) {
+        self.chunks.insert(chunk.position(), Connectivity::new(chunk, definitions));
+    }
+
+    pub fn remove(&mut self, position: [i32; 3]) {
+        self.chunks.remove(&position);
+    }
+
+    // chunks reachable from `start` through connected faces, never turning back
+    // towards the start, None if the start isn't loaded and nothing can be ruled out
+    pub fn visible(&self, start: [i32; 3], frustum: Option<&Frustum<f32>>) -> Option<HashSet<[i32; 3]>> {
+        if !self.chunks.contains_key(&start) {
+            return None;
+        }
+
+        let mut visible = HashSet::new();
+        let mut queue = VecDeque::new();
+        visible.insert(start);
+        queue.push_back((start, None, 0u8));
+
+        while let Some((position, entered, directions)) = queue.pop_front() {
+            let connectivity = match self.chunks.get(&position) {
+                Some(connectivity) => *connectivity,
+                None => continue,
+            };
+
+            for (face, normal) in FACES.iter().enumerate() {
+                if directions & (1 << opposite(face)) != 0 {
+                    continue;
+                }
+
+                if let Some(entered) = entered {
+                    if !connectivity.connects(entered, face) {
+                        continue;
+                    }
+                }
+
+                let next = [position[0] + normal[0], position[1] + normal[1], position[2] + normal[2]];
+                if visible.contains(&next) || !self.chunks.contains_key(&next) {
+                    continue;
+                }
+
+                if let Some(frustum) = frustum {
+                    if frustum.contains(&chunk::bounds(next)) == Relation::Out {
+                        continue;
+                    }
+                }
+
+                visible.insert(next);
+                queue.push_back((next, Some(opposite(face)), directions | 1 << face));
+            }
+        }
+
+        Some(visible)
+    }
+}